@@ -1,33 +1,72 @@
-use crate::data::{LeaderboardEntry, Position, TileType, Unit};
-use crate::utils;
+use crate::data::{
+    CellChange, ErrorCode, LeaderboardEntry, PaletteEntry, Position, Stance, Standing, TileType, Unit, UnitView, Viewport,
+};
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::fmt::{self, Formatter, Write};
 use uuid::Uuid;
 
 use rand::seq::SliceRandom;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
+use crate::config::GameConfig;
+use crate::path::{self, Goal};
+use crate::tiles::TileDef;
 use crate::constants;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct Board {
-    grid: HashMap<Position, Unit>,
+    config: Arc<GameConfig>,
+
+    // Committed generation, row-major. Every phase reads from here.
+    front: Vec<Unit>,
+
+    // Scratch generation the running phase writes into, swapped in on commit.
+    back: Vec<Unit>,
 
     // Map between team UUID and positions of their cells.
     teams: HashMap<Uuid, HashSet<Position>>,
 
     players: HashMap<Uuid, PlayerInformation>,
 
+    // Kept after a player is gone, for the end of game summary.
+    stats: HashMap<Uuid, PlayerStats>,
+
+    // Teams in the order they joined. A team's wire index is its position
+    // here plus one; zero stands for no team.
+    palette: Vec<Uuid>,
+    team_indices: HashMap<Uuid, u16>,
+
     // Map between the tiles and their positions.
     types: HashMap<TileType, HashSet<Position>>,
+
+    generation: usize,
+
+    // Cells written since the last `take_changes`, with what they held back
+    // then.
+    touched: HashMap<Position, Unit>,
+
+    // Every random decision of the simulation is drawn from here, so a board
+    // replays identically from its seed.
+    seed: u64,
+    rng: ChaCha8Rng,
+
+    // Cells pathfinding may still expand this generation.
+    path_budget: usize,
+
+    // Kills made this generation, paid out as bounties by the income rule.
+    kills: BTreeMap<Uuid, u32>,
 }
 
 impl fmt::Display for Board {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let mut s = String::new();
-        for y in 0..constants::Y_SIZE {
-            for x in 0..constants::X_SIZE {
+        for y in 0..self.config.y_size {
+            for x in 0..self.config.x_size {
                 write!(&mut s, "{}", self.get(Position::new(x, y)));
             }
             s.push('\n');
@@ -48,34 +87,30 @@ impl Unit {
         team: Uuid::nil(),
         hp: 0,
         am: 0,
-        target_pos: Position::new(0, 0),
+        target_pos: None,
+        stance: Stance::AGGRESSIVE,
     };
 
-    pub fn new_queen(team_id: Uuid, position: Position) -> Unit {
-        Unit {
-            tile: TileType::QUEEN,
-            team: team_id,
-            hp: constants::MAX_HP,
-            am: 0,
-            target_pos: position,
-        }
+    pub fn new_queen(config: &GameConfig, team_id: Uuid, position: Position) -> Unit {
+        Self::new_unit(config, team_id, position, TileType::QUEEN)
     }
 
-    pub fn new_unit(id: Uuid, position: Position, tile: TileType) -> Unit {
+    pub fn new_unit(config: &GameConfig, id: Uuid, position: Position, tile: TileType) -> Unit {
         Unit {
             tile,
             team: id,
-            hp: tile.get_base_hp(),
+            hp: config.base_hp(tile),
             am: 0,
-            target_pos: position,
+            target_pos: config.tile(tile).filter(|def| def.holds_position).map(|_| position),
+            stance: Stance::default(),
         }
     }
 
-    pub fn spawn_unit(&self, position: Position, tile: TileType) -> Unit {
+    pub fn spawn_unit(&self, config: &GameConfig, position: Position, tile: TileType) -> Unit {
         if tile == TileType::EMPTY {
             Unit::EMPTY
         } else {
-            Self::new_unit(self.team, position, tile)
+            Self::new_unit(config, self.team, position, tile)
         }
     }
 
@@ -100,146 +135,469 @@ impl fmt::Display for TileType {
             TileType::SPAWNER => "S",
             TileType::FEEDER => "F",
             TileType::BOLSTER => "B",
-            TileType::GUARD { .. } => "G",
-            TileType::ATTACK { .. } => "A",
+            TileType::GUARD => "G",
+            TileType::ATTACK => "A",
             TileType::QUEEN => "Q",
         }
         .fmt(f)
     }
 }
 
-#[derive(Clone)]
+impl TileType {
+    // The reverse of `Display`, for reading boards back in.
+    pub fn from_symbol(symbol: char) -> Option<Self> {
+        Some(match symbol {
+            ' ' | '.' => TileType::EMPTY,
+            'b' => TileType::BASE,
+            'S' => TileType::SPAWNER,
+            'F' => TileType::FEEDER,
+            'B' => TileType::BOLSTER,
+            'G' => TileType::GUARD,
+            'A' => TileType::ATTACK,
+            'Q' => TileType::QUEEN,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Intent {
+    Attack { target: Position, damage: u32 },
+    // Units walking back to their post are not chasing anything.
+    Move { target: Position, chasing: bool },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Move {
+    from: Position,
+    to: Position,
+    chasing: bool,
+}
+
+// The steps a generation is made of, in the order they run, except that
+// queens spawn first and every other spawner after bolstering, so new units
+// do not starve or get bolstered in the generation they appear.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Rule {
+    SPAWN,
+    STARVATION,
+    BOLSTER,
+    COMBAT,
+    INCOME,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] = [Rule::SPAWN, Rule::STARVATION, Rule::BOLSTER, Rule::COMBAT, Rule::INCOME];
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PutError {
+    OutOfBounds,
+    Occupied,
+    UnknownPlayer,
+    NotForSale,
+    InsufficientEnergy(u32),
+    WrongPhase,
+}
+
+impl PutError {
+    pub fn code(self) -> ErrorCode {
+        match self {
+            PutError::OutOfBounds => ErrorCode::OUT_OF_BOUNDS,
+            PutError::Occupied => ErrorCode::OCCUPIED,
+            PutError::UnknownPlayer => ErrorCode::NOT_IN_GAME,
+            PutError::NotForSale => ErrorCode::NOT_FOR_SALE,
+            PutError::InsufficientEnergy(_) => ErrorCode::INSUFFICIENT_ENERGY,
+            PutError::WrongPhase => ErrorCode::WRONG_PHASE,
+        }
+    }
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PutError::OutOfBounds => write!(f, "Position is outside of the board."),
+            PutError::Occupied => write!(f, "Position is already occupied."),
+            PutError::UnknownPlayer => write!(f, "You are not in the game."),
+            PutError::NotForSale => write!(f, "That tile can not be bought."),
+            PutError::InsufficientEnergy(cost) => {
+                write!(f, "Insufficient energy (cost is {}).", cost)
+            }
+            PutError::WrongPhase => write!(f, "Cells can only be placed during the build phase."),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OrderError {
+    OutOfBounds,
+    UnknownPlayer,
+}
+
+impl OrderError {
+    pub fn code(self) -> ErrorCode {
+        match self {
+            OrderError::OutOfBounds => ErrorCode::OUT_OF_BOUNDS,
+            OrderError::UnknownPlayer => ErrorCode::NOT_IN_GAME,
+        }
+    }
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::OutOfBounds => write!(f, "Rally point is outside of the board."),
+            OrderError::UnknownPlayer => write!(f, "You are not in the game."),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub peak_cells: usize,
+    pub kills: usize,
+    pub energy_spent: u32,
+    pub energy_earned: u32,
+    pub joined_at: usize,
+    pub died_at: Option<usize>,
+}
+
+impl PlayerStats {
+    pub fn generations_survived(&self, now: usize) -> usize {
+        self.died_at.unwrap_or(now) - self.joined_at
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PlayerInformation {
     pub id: Uuid,
     pub name: Option<String>,
     pub energy: u32,
+
+    // Secret handed to the client so it can resume this colony later. Nil
+    // until the server assigns one.
+    #[serde(default)]
+    pub token: Uuid,
+
+    // Generation the player's connection dropped at, if it is currently gone.
+    #[serde(default)]
+    pub disconnected_at: Option<usize>,
 }
 
 impl Board {
-    pub fn new() -> Self {
+    pub fn new(config: Arc<GameConfig>) -> Self {
+        Self::with_seed(config, rand::random())
+    }
+
+    pub fn with_seed(config: Arc<GameConfig>, seed: u64) -> Self {
+        let cells = config.x_size * config.y_size;
+
+        // Reading the word position of an RNG that has not generated a block
+        // yet underflows, so generate the first one up front. The stream is
+        // the same either way.
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_word_pos(0);
+
         Self {
-            grid: HashMap::new(),
+            config,
+            front: vec![Unit::EMPTY; cells],
+            back: vec![Unit::EMPTY; cells],
             teams: HashMap::new(),
             types: HashMap::new(),
             players: HashMap::new(),
+            stats: HashMap::new(),
+            palette: Vec::new(),
+            team_indices: HashMap::new(),
+            generation: 0,
+            touched: HashMap::new(),
+            seed,
+            rng,
+            path_budget: 0,
+            kills: BTreeMap::new(),
         }
     }
 
-    fn adj_position(
-        &self,
-        Position { x, y }: Position,
-        (dx, dy): (isize, isize),
-    ) -> Option<Position> {
-        if dx < 0 && x == 0
-            || dy < 0 && y == 0
-            || dx > 0 && x == constants::X_SIZE - 1
-            || dy > 0 && y == constants::Y_SIZE - 1
-        {
-            None
-        } else {
-            let x = if dx < 0 {
-                x - -dx as usize
-            } else {
-                x + dx as usize
-            };
+    pub fn config(&self) -> &GameConfig {
+        &self.config
+    }
 
-            let y = if dy < 0 {
-                y - -dy as usize
-            } else {
-                y + dy as usize
-            };
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn rng_word_pos(&self) -> u128 {
+        self.rng.get_word_pos()
+    }
+
+    // Fast-forwards the clock and RNG stream of a freshly seeded board, used
+    // when restoring a snapshot.
+    pub fn resume_at(&mut self, generation: usize, rng_word_pos: u128) {
+        self.generation = generation;
+        self.rng.set_word_pos(rng_word_pos);
+    }
 
-            Some(Position::new(x, y))
+    // Every team in palette order, with its stats, players that have left
+    // included.
+    pub fn teams(&self) -> impl Iterator<Item = (Uuid, PlayerStats)> + '_ {
+        self.palette
+            .iter()
+            .map(move |id| (*id, self.stats.get(id).cloned().unwrap_or_default()))
+    }
+
+    // Puts back the teams of a snapshot before its players are added again,
+    // so they keep their palette indices and stats.
+    pub fn restore_teams(&mut self, teams: Vec<(Uuid, PlayerStats)>) -> Option<()> {
+        for (id, stats) in teams {
+            if !self.team_indices.contains_key(&id) {
+                self.palette.push(id);
+                self.team_indices.insert(id, u16::try_from(self.palette.len()).ok()?);
+            }
+            self.stats.insert(id, stats);
         }
+        Some(())
+    }
+
+    pub fn units(&self) -> impl Iterator<Item = (Position, Unit)> + '_ {
+        self.front
+            .iter()
+            .enumerate()
+            .filter(|(_, unit)| unit.is_some())
+            .map(move |(i, &unit)| (self.config.position(i), unit))
+    }
+
+    pub fn in_bounds(&self, position: Position) -> bool {
+        self.config.in_bounds(position)
     }
 
     fn is_adj_position(&self, origin: Position, target: Position) -> bool {
         constants::ALL_OFFSETS
             .iter()
-            .filter_map(|&offset| self.adj_position(origin, offset))
+            .filter_map(|&offset| self.config.adj_position(origin, offset))
             .any(|pos| pos == target)
     }
 
-    fn adj_position_towards(&self, origin: Position, target: Position) -> Position {
-        let dx: isize = if target.x < origin.x {
-            -1
-        } else if target.x == origin.x {
-            0
-        } else {
-            1
-        };
-        let dy: isize = if target.y < origin.y {
-            -1
-        } else if target.y == origin.y {
-            0
-        } else {
-            1
-        };
-        self.adj_position(origin, (dx, dy)).unwrap()
-    }
+    // Next cell on the way to `goal`, going around whatever is in the way
+    // while this generation's pathfinding budget lasts, and straight at it once
+    // it runs out or when no path turns up within the search limit. `None` if
+    // the unit is already there.
+    fn step_towards(&mut self, from: Position, goal: Goal) -> Option<Position> {
+        if self.path_budget == 0 {
+            return path::greedy_step(&self.config, from, goal);
+        }
 
-    fn move_unit(&mut self, origin: Position, target: Position) {
-        let unit = self.delete(origin);
-        self.set(target, unit);
+        // Friendly fighters may step aside this generation, and if they do not
+        // the move just bounces.
+        let limit = self.path_budget.min(constants::PATH_SEARCH_LIMIT);
+        let (config, front) = (&self.config, &self.front);
+        let team = front[config.index(from)].team;
+        let passable = |p| {
+            let unit = front[config.index(p)];
+            unit.is_empty() || (unit.team == team && config.is_mobile(unit.tile))
+        };
+        let (step, expanded) = path::first_step(config, from, goal, passable, limit);
+        self.path_budget -= expanded;
+        // Out of reach within the limit, or no way through at all. Heading
+        // straight at it beats standing still.
+        step.or_else(|| path::greedy_step(config, from, goal))
     }
 
     fn adj_unit(&self, position: Position, offset: (isize, isize)) -> Option<Unit> {
-        self.adj_position(position, offset).map(|pos| self.get(pos))
+        self.config.adj_position(position, offset).map(|pos| self.get(pos))
     }
 
     pub fn get(&self, pos: Position) -> Unit {
-        self.grid.get(&pos).copied().unwrap_or(Unit::EMPTY)
+        self.front[self.config.index(pos)]
     }
 
     pub fn get_mut(&mut self, pos: Position) -> &mut Unit {
-        utils::get_mut_or_put(&mut self.grid, pos, || Unit::EMPTY)
+        self.touch(pos);
+        &mut self.front[self.config.index(pos)]
     }
 
     pub fn set(&mut self, position: Position, unit: Unit) {
         self.delete(position);
-        utils::get_mut_or_put(&mut self.types, unit.tile, || HashSet::new()).insert(position);
-        utils::get_mut_or_put(&mut self.teams, unit.team, || HashSet::new()).insert(position);
-        self.grid.insert(position, unit);
+        self.index_insert(position, unit);
+        self.front[self.config.index(position)] = unit;
     }
 
     pub fn delete(&mut self, position: Position) -> Unit {
-        let unit = self.get(position);
-        self.types
-            .get_mut(&unit.tile)
-            .map(|set| set.remove(&position));
-        self.teams
-            .get_mut(&unit.team)
-            .map(|set| set.remove(&position));
-        self.grid.remove(&position);
+        self.touch(position);
+        let i = self.config.index(position);
+        let unit = std::mem::replace(&mut self.front[i], Unit::EMPTY);
+        self.index_remove(position, unit);
         unit
     }
 
+    // Before the first write to a cell (in either buffer) the committed cell
+    // still holds what was last published.
+    fn touch(&mut self, position: Position) {
+        let unit = self.front[self.config.index(position)];
+        self.touched.entry(position).or_insert(unit);
+    }
+
+    fn index_insert(&mut self, position: Position, unit: Unit) {
+        if unit.is_some() {
+            self.types.entry(unit.tile).or_default().insert(position);
+            self.teams.entry(unit.team).or_default().insert(position);
+        }
+    }
+
+    fn index_remove(&mut self, position: Position, unit: Unit) {
+        if unit.is_some() {
+            if let Some(set) = self.types.get_mut(&unit.tile) {
+                set.remove(&position);
+            }
+            if let Some(set) = self.teams.get_mut(&unit.team) {
+                set.remove(&position);
+            }
+        }
+    }
+
+    // Positions of every unit of a tile type, copied out so that a phase can
+    // stage writes (which update the indices) while walking them. Sorted so
+    // the walk order, and with it the RNG draws, does not depend on hashing.
+    fn positions_of(&self, tile: TileType) -> Vec<Position> {
+        let mut vec = self
+            .types
+            .get(&tile)
+            .map_or_else(Vec::new, |set| set.iter().copied().collect::<Vec<_>>());
+        vec.sort_unstable();
+        vec
+    }
+
+    fn team_positions(&self) -> Vec<(Uuid, Vec<Position>)> {
+        let mut vec = self
+            .teams
+            .iter()
+            .map(|(&id, set)| {
+                let mut list = set.iter().copied().collect::<Vec<_>>();
+                list.sort_unstable();
+                (id, list)
+            })
+            .collect::<Vec<_>>();
+        vec.sort_unstable_by_key(|&(id, _)| id);
+        vec
+    }
+
+    // Phases read the committed `front` buffer and write into `back`. The
+    // indices always describe the most recently written state, so during a
+    // phase they follow `back`.
+    fn stage(&mut self) {
+        self.back.copy_from_slice(&self.front);
+    }
+
+    fn commit(&mut self) {
+        std::mem::swap(&mut self.front, &mut self.back);
+    }
+
+    fn staged(&self, pos: Position) -> Unit {
+        self.back[self.config.index(pos)]
+    }
+
+    fn staged_mut(&mut self, pos: Position) -> &mut Unit {
+        self.touch(pos);
+        &mut self.back[self.config.index(pos)]
+    }
+
+    fn stage_set(&mut self, position: Position, unit: Unit) {
+        self.stage_delete(position);
+        self.index_insert(position, unit);
+        self.back[self.config.index(position)] = unit;
+    }
+
+    fn stage_delete(&mut self, position: Position) -> Unit {
+        self.touch(position);
+        let i = self.config.index(position);
+        let unit = std::mem::replace(&mut self.back[i], Unit::EMPTY);
+        self.index_remove(position, unit);
+        unit
+    }
+
+    fn stage_move(&mut self, origin: Position, target: Position) {
+        let unit = self.stage_delete(origin);
+        self.stage_set(target, unit);
+    }
+
+    fn stage_remove_team(&mut self, id: Uuid) {
+        if let Some(set) = self.teams.remove(&id) {
+            for pos in set {
+                self.touch(pos);
+                let unit = std::mem::replace(&mut self.back[self.config.index(pos)], Unit::EMPTY);
+                if let Some(set) = self.types.get_mut(&unit.tile) {
+                    set.remove(&pos);
+                }
+            }
+        }
+        self.players.remove(&id);
+
+        // Only called while a generation is being worked out.
+        self.mark_dead(id, self.generation + 1);
+    }
+
     pub fn next(&mut self) {
-        self.queen_gen();
-        self.feeder_gen();
-        self.bolster_gen();
-        self.spawner_gen();
-        self.guard_gen();
-        self.attacker_gen();
-    }
-
-    fn queen_gen(&mut self) {
-        let mut new_board = self.clone();
-
-        // Queen will fill one cell as close to itself as possible with a base unit (equidistant is chosen randomly)
-        // if let Some(vec) = self.types.get(&TileType::QUEEN) {
-        self.types.get(&TileType::QUEEN).map(|vec| {
-            vec.iter().for_each(|&queen_pos| {
-                if let Some(base_pos) = self.nearest_unoccupied_position(queen_pos, 1) {
-                    new_board.set(
-                        base_pos,
-                        self.get(queen_pos).spawn_unit(base_pos, TileType::BASE),
-                    )
+        self.next_with(&Rule::ALL);
+    }
+
+    // Runs a generation with only some of the rules, so they can be looked at
+    // in isolation.
+    pub fn next_with(&mut self, rules: &[Rule]) {
+        let runs = |rule| rules.contains(&rule);
+        if runs(Rule::SPAWN) {
+            self.spawn_gen(|tile| tile == TileType::QUEEN);
+        }
+        if runs(Rule::STARVATION) {
+            self.starvation_gen();
+        }
+        if runs(Rule::BOLSTER) {
+            self.bolster_gen();
+        }
+        if runs(Rule::SPAWN) {
+            self.spawn_gen(|tile| tile != TileType::QUEEN);
+        }
+        if runs(Rule::COMBAT) {
+            self.combat_gen();
+        }
+        if runs(Rule::INCOME) {
+            self.income_gen();
+        }
+        // Kills only pay out in the generation they are made.
+        self.kills.clear();
+        self.generation += 1;
+
+        for (id, set) in &self.teams {
+            if let Some(stats) = self.stats.get_mut(id) {
+                stats.peak_cells = stats.peak_cells.max(set.len());
+            }
+        }
+    }
+
+    // Every tile with a spawn table fills one cell as close to itself as
+    // possible (equidistant is chosen randomly), one kind of tile at a time.
+    fn spawn_gen(&mut self, spawns: impl Fn(TileType) -> bool) {
+        let config = Arc::clone(&self.config);
+        for (tile, def) in config
+            .tiles
+            .iter()
+            .filter(|&(tile, def)| spawns(tile) && !def.spawns.is_empty())
+        {
+            self.stage();
+
+            for pos in self.positions_of(tile) {
+                if let Some(unit_pos) = self.nearest_unoccupied_position(pos, def.spawn_range) {
+                    if let Some(spawn) = def.pick_spawn(&mut self.rng) {
+                        let unit = self.get(pos).spawn_unit(&config, unit_pos, spawn);
+                        self.stage_set(unit_pos, unit);
+                    }
                 }
-            });
-        });
+            }
 
-        *self = new_board;
+            self.commit();
+        }
     }
 
     pub fn get_player(&self, id: Uuid) -> Option<&PlayerInformation> {
@@ -250,205 +608,564 @@ impl Board {
         self.players.get_mut(&id)
     }
 
-    pub fn add_player(&mut self, player: PlayerInformation) {
+    // Returns the player's team index, or `None` if every index is taken.
+    pub fn add_player(&mut self, player: PlayerInformation) -> Option<u16> {
+        let index = self.claim_index(player.id)?;
+        let generation = self.generation;
+        self.stats.entry(player.id).or_insert_with(|| PlayerStats {
+            joined_at: generation,
+            ..PlayerStats::default()
+        });
         self.players.insert(player.id, player);
+        Some(index)
     }
 
-    pub fn remove_player(&mut self, id: Uuid) {
-        let mut new_board = self.clone();
-        self.teams.get(&id).map(|set| {
-            set.iter().for_each(|&pos| {
-                new_board.delete(pos);
+    // A team keeps its index for as long as it has a player or units on the
+    // board. After that the index, and the stats that went with it, go to the
+    // next team to join, so the palette of a long running board stays about as
+    // big as the most teams it has had at once.
+    fn claim_index(&mut self, id: Uuid) -> Option<u16> {
+        if let Some(&index) = self.team_indices.get(&id) {
+            return Some(index);
+        }
+
+        let gone = |team: &Uuid| {
+            !self.players.contains_key(team) && self.teams.get(team).is_none_or(|set| set.is_empty())
+        };
+        let slot = match self.palette.iter().position(gone) {
+            Some(slot) => {
+                let old = std::mem::replace(&mut self.palette[slot], id);
+                self.team_indices.remove(&old);
+                self.stats.remove(&old);
+                self.teams.remove(&old);
+                slot
+            }
+            None => {
+                self.palette.push(id);
+                self.palette.len() - 1
+            }
+        };
+
+        // Zero is no team, so the last index to hand out is `u16::MAX`.
+        match u16::try_from(slot + 1) {
+            Ok(index) => {
+                self.team_indices.insert(id, index);
+                Some(index)
+            }
+            Err(_) => {
+                self.palette.pop();
+                None
+            }
+        }
+    }
+
+    pub fn stats(&self, id: Uuid) -> Option<&PlayerStats> {
+        self.stats.get(&id)
+    }
+
+    // How a player is doing right now, dead or alive.
+    pub fn standing(&self, id: Uuid) -> Standing {
+        let stats = self.stats(id).cloned().unwrap_or_default();
+        Standing {
+            id,
+            name: self.get_player(id).and_then(|player| player.name.clone()),
+            cells: self.team_size(id),
+            alive: self.queen_position(id).is_some(),
+            peak_cells: stats.peak_cells,
+            kills: stats.kills,
+            energy_spent: stats.energy_spent,
+            energy_earned: stats.energy_earned,
+            generations_survived: stats.generations_survived(self.generation),
+        }
+    }
+
+    fn mark_dead(&mut self, id: Uuid, generation: usize) {
+        if let Some(stats) = self.stats.get_mut(&id) {
+            stats.died_at.get_or_insert(generation);
+        }
+    }
+
+    pub fn team_index(&self, id: Uuid) -> u16 {
+        self.team_indices.get(&id).copied().unwrap_or(0)
+    }
+
+    pub fn palette(&self) -> Vec<PaletteEntry> {
+        self.palette
+            .iter()
+            .map(|&id| PaletteEntry {
+                team: self.team_index(id),
+                id,
+                name: self.get_player(id).and_then(|player| player.name.clone()),
+            })
+            .collect()
+    }
+
+    pub fn view(&self, unit: Unit) -> UnitView {
+        UnitView {
+            tile: unit.tile,
+            team: self.team_index(unit.team),
+            hp: unit.hp,
+            am: unit.am,
+        }
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &PlayerInformation> {
+        self.players.values()
+    }
+
+    pub fn players_mut(&mut self) -> impl Iterator<Item = &mut PlayerInformation> {
+        self.players.values_mut()
+    }
+
+    pub fn find_player_by_token(&self, token: Uuid) -> Option<Uuid> {
+        if token.is_nil() {
+            return None;
+        }
+        self.players
+            .values()
+            .find(|player| player.token == token)
+            .map(|player| player.id)
+    }
+
+    // Players whose connection has been gone for at least `grace` generations.
+    pub fn expired_players(&self, grace: usize) -> Vec<Uuid> {
+        let mut vec = self
+            .players
+            .values()
+            .filter(|player| {
+                player
+                    .disconnected_at
+                    .is_some_and(|gen| self.generation >= gen + grace)
             })
+            .map(|player| player.id)
+            .collect::<Vec<_>>();
+        vec.sort_unstable();
+        vec
+    }
+
+    pub fn queen_position(&self, id: Uuid) -> Option<Position> {
+        self.types
+            .get(&TileType::QUEEN)?
+            .iter()
+            .copied()
+            .find(|&pos| self.get(pos).team == id)
+    }
+
+    pub fn team_size(&self, id: Uuid) -> usize {
+        self.teams.get(&id).map_or(0, HashSet::len)
+    }
+
+    // Registers a player and drops their queen and care package somewhere
+    // away from everyone else. Returns the queen's position.
+    pub fn spawn_player(&mut self, id: Uuid) -> Option<Position> {
+        let spawn_pos = self.find_random_safe_position(5)?;
+        let index = self.add_player(PlayerInformation {
+            id,
+            name: None,
+            energy: self.config.init_erg,
+            token: Uuid::nil(),
+            disconnected_at: None,
         });
+        if index.is_none() {
+            warn!("Every team index is in use, turning player {} away.", id);
+            return None;
+        }
+
+        // care package
+        let queen = Unit::new_queen(&self.config, id, spawn_pos);
+        self.set(spawn_pos, queen);
+
+        let feeder_pos = Position { x: spawn_pos.x, y: spawn_pos.y + 1 };
+        self.set(feeder_pos, queen.spawn_unit(&self.config, feeder_pos, TileType::FEEDER));
+
+        Some(spawn_pos)
+    }
+
+    // Places a tile bought by a player. Returns the player's remaining energy.
+    pub fn put(&mut self, id: Uuid, position: Position, tile: TileType) -> Result<u32, PutError> {
+        if !self.in_bounds(position) {
+            return Err(PutError::OutOfBounds);
+        }
+        if self.get(position).is_some() {
+            return Err(PutError::Occupied);
+        }
+
+        let cost = self.config.cost(tile).ok_or(PutError::NotForSale)?;
+        let player = self.players.get_mut(&id).ok_or(PutError::UnknownPlayer)?;
+        if cost >= player.energy {
+            return Err(PutError::InsufficientEnergy(cost));
+        }
+        player.energy -= cost;
+        let energy = player.energy;
+        if let Some(stats) = self.stats.get_mut(&id) {
+            stats.energy_spent += cost;
+        }
+
+        self.set(position, Unit::new_unit(&self.config, id, position, tile));
+        Ok(energy)
+    }
+
+    // Sends the player's fighters inside `area` to `position`, where they
+    // will go back to whenever they have nothing to fight. Returns how many
+    // took the order.
+    pub fn set_rally(&mut self, id: Uuid, area: Viewport, position: Position) -> Result<usize, OrderError> {
+        if !self.in_bounds(position) {
+            return Err(OrderError::OutOfBounds);
+        }
+        self.order(id, area, |unit| unit.target_pos = Some(position))
+    }
+
+    pub fn set_stance(&mut self, id: Uuid, area: Viewport, stance: Stance) -> Result<usize, OrderError> {
+        self.order(id, area, |unit| unit.stance = stance)
+    }
+
+    fn order(&mut self, id: Uuid, area: Viewport, order: impl Fn(&mut Unit)) -> Result<usize, OrderError> {
+        if !self.players.contains_key(&id) {
+            return Err(OrderError::UnknownPlayer);
+        }
+        let mut units = self
+            .teams
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|&pos| area.contains(pos) && self.config.is_mobile(self.get(pos).tile))
+            .collect::<Vec<_>>();
+        units.sort_unstable();
+
+        for &pos in &units {
+            order(self.get_mut(pos));
+        }
+        Ok(units.len())
+    }
+
+    pub fn remove_player(&mut self, id: Uuid) {
+        if let Some(set) = self.teams.remove(&id) {
+            for pos in set {
+                self.touch(pos);
+                let unit = std::mem::replace(&mut self.front[self.config.index(pos)], Unit::EMPTY);
+                if let Some(set) = self.types.get_mut(&unit.tile) {
+                    set.remove(&pos);
+                }
+            }
+        }
         self.players.remove(&id);
-        new_board.teams.remove(&id);
-        *self = new_board;
+        self.mark_dead(id, self.generation);
     }
 
-    fn feeder_gen(&mut self) {
-        let mut new_board = self.clone();
+    // Units out of every friendly feed range starve, harder the further they
+    // are from a friendly queen or feeder. Food sources never starve. Food
+    // only travels through a team's own cells and empty ones, so a colony cut
+    // off by enemy lines starves even when its feeders are close by.
+    fn starvation_gen(&mut self) {
+        let config = Arc::clone(&self.config);
+        let feed_range = |tile| config.tile(tile).and_then(|def| def.feed_range);
+        let reach = config.starvation_reach();
+
+        self.stage();
+
+        'z: for (team_id, list) in self.team_positions() {
+            let (fed, near) = {
+                let (config, front) = (&self.config, &self.front);
+                let passable = |p| {
+                    let unit = front[config.index(p)];
+                    unit.is_empty() || unit.team == team_id
+                };
+                let sources = list
+                    .iter()
+                    .filter_map(|&p| feed_range(front[config.index(p)].tile).map(|range| (p, range)))
+                    .collect::<Vec<_>>();
+                let fed = Self::flood(config, &sources, passable);
+                let near = Self::flood(config, &sources.iter().map(|&(p, _)| (p, reach)).collect::<Vec<_>>(), passable);
+                (fed, near)
+            };
+
+            for pos in list {
+                if feed_range(self.get(pos).tile).is_some() || fed.contains_key(&pos) {
+                    continue;
+                }
 
-        // All units not within a friendly Feeder’s range loose 1hp due to Starvation,
-        // if farther away than 10 tiles from Queen or a Feeder, loose 3hp
-        'z: for (team_id, list) in &self.teams {
-            for &pos in list
-                .iter()
-                .filter(|&&pos| !self.within_friendly_range(pos, TileType::FEEDER, 5))
-                .filter(|&&pos| self.get(pos).tile != TileType::FEEDER)
-            {
-                let unit = new_board.get_mut(pos);
-                unit.hp = unit.hp.saturating_sub(1);
+                let distance = near.get(&pos).map(|left| reach - left);
+                let drain = config.starvation_drain(distance);
+
+                let unit = self.staged_mut(pos);
+                unit.hp = unit.hp.saturating_sub(drain);
                 if unit.hp == 0 {
                     if unit.tile == TileType::QUEEN {
-                        let team = unit.team;
-                        new_board.remove_player(team);
+                        self.stage_remove_team(team_id);
                         continue 'z;
                     } else {
-                        new_board.delete(pos);
+                        self.stage_delete(pos);
                     }
                 }
             }
         }
 
-        // Spawners spawn
+        self.commit();
+    }
 
-        // Attacker move and lock in
+    fn bolster_gen(&mut self) {
+        let config = Arc::clone(&self.config);
+        let bolsters = config
+            .tiles
+            .iter()
+            .filter_map(|(tile, def)| def.bolster_range.map(|range| (tile, range)))
+            .collect::<Vec<_>>();
+
+        self.stage();
+
+        for (_, list) in self.team_positions() {
+            for pos in list {
+                if bolsters
+                    .iter()
+                    .any(|&(bolster, range)| self.within_friendly_range(pos, bolster, range))
+                {
+                    let cap = config.armor_cap(self.get(pos).tile);
+                    let unit = self.staged_mut(pos);
+                    unit.am = unit.am.saturating_add(1).min(cap);
+                }
+            }
+        }
 
-        *self = new_board;
+        self.commit();
     }
 
-    fn bolster_gen(&mut self) {
-        let mut new_board = self.clone();
-        self.teams.iter().for_each(|(team_id, list)| {
-            list.iter()
-                .filter(|&&pos| self.within_friendly_range(pos, TileType::BOLSTER, 3))
-                .for_each(|&pos| {
-                    let unit = new_board.get_mut(pos);
-                    unit.am = unit.am.saturating_add(1);
-                    if unit.am > constants::MAX_AM {
-                        unit.am = constants::MAX_AM;
-                    }
-                });
-        });
+    // Every colony with a queen earns the base income, a share for the cells
+    // it holds and whatever its tiles make. Kills made this generation are
+    // paid out here too, so all energy comes in under this rule.
+    fn income_gen(&mut self) {
+        let config = Arc::clone(&self.config);
+        let mut earned = BTreeMap::<Uuid, u32>::new();
 
-        *self = new_board;
-    }
-
-    fn spawner_gen(&mut self) {
-        let mut new_board = self.clone();
-        let mut rng = rand::thread_rng();
-
-        self.types.get(&TileType::SPAWNER).map(|vec| {
-            vec.iter().for_each(|&spawner_pos| {
-                if let Some(unit_pos) = self.nearest_unoccupied_position(spawner_pos, 5) {
-                    // new_board.set(base_pos, self.get(spawner_pos).spawn_base())
-                    let tile = match rng.gen_range(0, 100) {
-                        0..=94 => TileType::BASE,
-                        95 => TileType::ATTACK,
-                        96 => TileType::SPAWNER,
-                        97 => TileType::FEEDER,
-                        98 => TileType::BOLSTER,
-                        99 => TileType::GUARD,
-                        _ => unreachable!(),
-                    };
-                    new_board.set(unit_pos, self.get(spawner_pos).spawn_unit(unit_pos, tile))
+        for (id, set) in &self.teams {
+            let territory = set.len().checked_div(config.income.cells_per_erg).unwrap_or(0);
+            earned.insert(*id, config.income.base.saturating_add(territory as u32));
+        }
+        for (tile, def) in config.tiles.iter().filter(|(_, def)| def.income > 0) {
+            for pos in self.positions_of(tile) {
+                if let Some(erg) = earned.get_mut(&self.get(pos).team) {
+                    *erg = erg.saturating_add(def.income);
                 }
-            });
-        });
+            }
+        }
 
-        *self = new_board;
-    }
-
-    fn guard_gen(&mut self) {
-        let mut new_board = self.clone();
-
-        if let Some(vec) = self.types.get(&TileType::GUARD) {
-            for &guard_pos in vec {
-                if let Some(enemy_pos) = self.nearest_enemy_position(guard_pos, 3) {
-                    if self.is_adj_position(guard_pos, enemy_pos) {
-                        let target_unit = new_board.get_mut(enemy_pos);
-
-                        if target_unit.am != 0 {
-                            if target_unit.am < constants::ATK_DMG {
-                                target_unit.hp = target_unit
-                                    .hp
-                                    .saturating_sub(constants::GRD_DMG - target_unit.am);
-                                target_unit.am = 0;
-                            } else {
-                                target_unit.am = target_unit.am.saturating_sub(constants::GRD_DMG);
-                            }
-                        } else {
-                            target_unit.hp = target_unit.hp.saturating_sub(constants::GRD_DMG);
-                        }
+        for (id, erg) in earned {
+            if self.queen_position(id).is_some() {
+                self.earn(id, erg);
+            }
+        }
 
-                        if target_unit.hp == 0 {
-                            if target_unit.tile == TileType::QUEEN {
-                                let team = target_unit.team;
-                                new_board.remove_player(team);
-                            }
-                            new_board.move_unit(guard_pos, enemy_pos);
-                        }
-                    } else {
-                        let target = self.adj_position_towards(guard_pos, enemy_pos);
-                        new_board.move_unit(guard_pos, target);
+        // Bounties are owed whether or not the killer still has a queen.
+        for (id, kills) in std::mem::take(&mut self.kills) {
+            self.earn(id, kills.saturating_mul(config.income.kill_bounty));
+        }
+    }
+
+    fn earn(&mut self, id: Uuid, erg: u32) {
+        if let Some(player) = self.players.get_mut(&id) {
+            player.energy = player.energy.saturating_add(erg);
+            if let Some(stats) = self.stats.get_mut(&id) {
+                stats.energy_earned = stats.energy_earned.saturating_add(erg);
+            }
+        }
+    }
+
+    // Tiles with an aggro radius chase down the nearest enemy in it and hit it
+    // once they are adjacent. Every fighter makes up its mind looking at the
+    // same board and all hits land at once, so a unit that dies this
+    // generation still strikes back and no kind of tile gets to go first.
+    fn combat_gen(&mut self) {
+        let config = Arc::clone(&self.config);
+        self.path_budget = config.path_budget;
+        let mut intents = Vec::new();
+        for (tile, def) in config.tiles.iter() {
+            if let Some(radius) = def.aggro_radius {
+                for pos in self.positions_of(tile) {
+                    if let Some(intent) = self.intent(pos, def, radius) {
+                        intents.push((pos, intent));
                     }
+                }
+            }
+        }
+
+        self.stage();
+
+        let mut hits = BTreeMap::<Position, Vec<(Uuid, u32)>>::new();
+        for &(pos, intent) in &intents {
+            if let Intent::Attack { target, damage } = intent {
+                hits.entry(target).or_default().push((self.get(pos).team, damage));
+            }
+        }
+
+        for (target, hits) in hits {
+            // Gone with its queen earlier in the loop.
+            if self.staged(target).is_empty() {
+                continue;
+            }
+
+            let damage = hits.iter().map(|&(_, damage)| damage).sum::<u32>();
+            let unit = self.staged_mut(target);
+
+            // Armor soaks up damage before hp does.
+            if unit.am < damage {
+                unit.hp = unit.hp.saturating_sub(damage - unit.am);
+                unit.am = 0;
+            } else {
+                unit.am -= damage;
+            }
+
+            if unit.hp == 0 {
+                let (tile, team) = (unit.tile, unit.team);
+                if tile == TileType::QUEEN {
+                    self.stage_remove_team(team);
                 } else {
-                    let target_pos = self.get(guard_pos).target_pos;
-                    if guard_pos != target_pos {
-                        let target = self.adj_position_towards(guard_pos, target_pos);
-                        if self.get(target).is_empty() {
-                            new_board.move_unit(guard_pos, target);
-                        }
+                    self.stage_delete(target);
+                }
+
+                // The kill goes to whichever team did the most damage.
+                let mut dealt = BTreeMap::<Uuid, u32>::new();
+                for &(killer, damage) in &hits {
+                    *dealt.entry(killer).or_default() += damage;
+                }
+                let killer = dealt.into_iter().max_by_key(|&(id, damage)| (damage, Reverse(id)));
+                if let Some((id, _)) = killer {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.kills += 1;
                     }
+                    *self.kills.entry(id).or_default() += 1;
                 }
             }
         }
 
-        *self = new_board;
+        let moves = intents
+            .iter()
+            .filter_map(|&(from, intent)| match intent {
+                Intent::Move { target, chasing } => Some(Move { from, to: target, chasing }),
+                Intent::Attack { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        self.resolve_moves(moves);
+
+        self.commit();
     }
 
-    fn attacker_gen(&mut self) {
-        let mut new_board = self.clone();
+    // Moves everyone that can go where they want, all at once, without ever
+    // landing on another unit. A unit can follow another out of its cell and
+    // two friends can swap places. When several units want the same cell the
+    // ones chasing an enemy go before guards walking back to their post, then
+    // the one furthest left, then highest up; everyone else stays put, which can
+    // in turn block whoever wanted to follow them.
+    fn resolve_moves(&mut self, moves: Vec<Move>) {
+        // Units that died in combat stay dead.
+        let mut moves = moves
+            .into_iter()
+            .filter(|m| self.staged(m.from).is_some())
+            .collect::<Vec<_>>();
+        moves.sort_by_key(|m| (!m.chasing, m.from));
+
+        let leaving = moves
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.from, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut active = vec![true; moves.len()];
+        loop {
+            // Sorted by priority, so the first claim on a cell wins.
+            let mut claims = HashMap::new();
+            for (i, m) in moves.iter().enumerate().filter(|&(i, _)| active[i]) {
+                claims.entry(m.to).or_insert(i);
+            }
 
-        if let Some(vec) = self.types.get(&TileType::ATTACK) {
-            for &attacker_pos in vec {
-                if let Some(enemy_pos) = self.nearest_enemy_position(attacker_pos, 5) {
-                    if self.is_adj_position(attacker_pos, enemy_pos) {
-                        let target_unit = new_board.get_mut(enemy_pos);
+            let mut changed = false;
+            for i in 0..moves.len() {
+                if !active[i] {
+                    continue;
+                }
+                let Move { from, to, .. } = moves[i];
 
-                        if target_unit.am != 0 {
-                            if target_unit.am < constants::ATK_DMG {
-                                target_unit.hp = target_unit
-                                    .hp
-                                    .saturating_sub(constants::ATK_DMG - target_unit.am);
-                                target_unit.am = 0;
-                            } else {
-                                target_unit.am = target_unit.am.saturating_sub(constants::ATK_DMG);
-                            }
-                        } else {
-                            target_unit.hp = target_unit.hp.saturating_sub(constants::ATK_DMG);
-                        }
+                let free = match leaving.get(&to).copied().filter(|&j| active[j]) {
+                    None => self.staged(to).is_empty(),
+                    Some(j) if moves[j].to == from => self.staged(to).is_same_team_as(self.staged(from)),
+                    Some(_) => true,
+                };
 
-                        if target_unit.hp == 0 {
-                            if target_unit.tile == TileType::QUEEN {
-                                let team = target_unit.team;
-                                new_board.remove_player(team);
-                            }
-                            new_board.move_unit(attacker_pos, enemy_pos);
-                        }
-                    } else {
-                        let target = self.adj_position_towards(attacker_pos, enemy_pos);
-                        if self.get(target).is_empty() {
-                            new_board.move_unit(attacker_pos, target);
-                        }
-                    }
+                if claims[&to] != i || !free {
+                    active[i] = false;
+                    changed = true;
                 }
             }
+            if !changed {
+                break;
+            }
         }
 
-        *self = new_board;
+        let moving = moves
+            .iter()
+            .zip(&active)
+            .filter(|&(_, &active)| active)
+            .map(|(m, _)| (self.stage_delete(m.from), m.to))
+            .collect::<Vec<_>>();
+        for (unit, to) in moving {
+            self.stage_set(to, unit);
+        }
     }
 
-    pub fn find_random_safe_position(&self, distance: usize) -> Option<Position> {
-        let mut rng = rand::thread_rng();
+    // What a fighter wants to do this generation.
+    fn intent(&mut self, pos: Position, def: &TileDef, radius: u16) -> Option<Intent> {
+        let unit = self.get(pos);
+        let enemy = match unit.stance {
+            Stance::AGGRESSIVE => self.nearest_enemy_position(pos, radius, None),
+            Stance::HOLD => self.nearest_enemy_position(pos, 1, None),
+            Stance::PATROL => {
+                let post = unit.target_pos.unwrap_or(pos);
+                let depth = pos.distance(post).min(u16::MAX as usize) as u16;
+                self.nearest_enemy_position(pos, radius.saturating_add(depth), Some((post, radius)))
+            }
+        };
+
+        if let Some(enemy_pos) = enemy {
+            if self.is_adj_position(pos, enemy_pos) {
+                Some(Intent::Attack {
+                    target: enemy_pos,
+                    damage: def.damage,
+                })
+            } else {
+                self.step_towards(pos, Goal::NextTo(enemy_pos))
+                    .map(|target| Intent::Move { target, chasing: true })
+            }
+        } else if let Some(target_pos) = unit.target_pos {
+            // A group sent to one rally point gathers around it: each unit
+            // heads for the free cell closest to it, as long as that is closer
+            // than where it already stands.
+            let closer = pos.distance(target_pos).saturating_sub(1);
+            let depth = closer.min(constants::RALLY_SPREAD) as u16;
+            let (config, front) = (&self.config, &self.front);
+            let spot = Self::bfs(config, None, target_pos, depth, |p| front[config.index(p)].is_empty())?;
+            self.step_towards(pos, Goal::At(spot))
+                .map(|target| Intent::Move { target, chasing: false })
+        } else {
+            None
+        }
+    }
 
+    pub fn find_random_safe_position(&mut self, distance: usize) -> Option<Position> {
         for _ in 0..50 {
-            let x = rng.gen_range(0, constants::X_SIZE);
-            let y = rng.gen_range(0, constants::Y_SIZE);
+            let x = self.rng.gen_range(0, self.config.x_size);
+            let y = self.rng.gen_range(0, self.config.y_size);
 
             if x <= distance
-                || x >= constants::X_SIZE - distance
+                || x >= self.config.x_size - distance
                 || y <= distance
-                || y >= constants::Y_SIZE - distance
+                || y >= self.config.y_size - distance
             {
                 continue;
             }
 
             let position = Position::new(x, y);
 
-            if self
-                .bfs(position, distance as u16, false, |p| self.get(p).is_some())
+            let (config, front) = (&self.config, &self.front);
+            if Self::bfs(config, None, position, distance as u16, |p| front[config.index(p)].is_some())
                 .is_none()
             {
                 return Some(position);
@@ -458,18 +1175,30 @@ impl Board {
     }
 
     // BFS the grid
-    fn nearest_unoccupied_position(&self, position: Position, max_depth: u16) -> Option<Position> {
-        self.bfs(position, max_depth, true, |pos| self.get(pos).is_empty())
+    fn nearest_unoccupied_position(&mut self, position: Position, max_depth: u16) -> Option<Position> {
+        let (config, front) = (&self.config, &self.front);
+        Self::bfs(config, Some(&mut self.rng), position, max_depth, |pos| {
+            front[config.index(pos)].is_empty()
+        })
     }
 
-    fn nearest_enemy_position(&self, position: Position, max_depth: u16) -> Option<Position> {
+    // With a leash, only enemies within that range of its centre count.
+    fn nearest_enemy_position(
+        &mut self,
+        position: Position,
+        max_depth: u16,
+        leash: Option<(Position, u16)>,
+    ) -> Option<Position> {
         let unit = self.get(position);
         if unit.is_empty() {
             None
         } else {
-            self.bfs(position, max_depth, true, |pos| {
-                let target = self.get(pos);
-                target.is_some() && !target.is_same_team_as(unit)
+            let (config, front) = (&self.config, &self.front);
+            Self::bfs(config, Some(&mut self.rng), position, max_depth, |pos| {
+                let target = front[config.index(pos)];
+                target.is_some()
+                    && !target.is_same_team_as(unit)
+                    && leash.is_none_or(|(centre, range)| pos.distance(centre) <= range as usize)
             })
         }
     }
@@ -479,14 +1208,82 @@ impl Board {
         if unit.is_empty() {
             false
         } else {
-            self.bfs(position, max_depth, false, |pos| self.get(pos).tile == tile)
-                .map_or(false, |fpos| self.get(fpos).is_same_team_as(unit))
+            Self::bfs(&self.config, None, position, max_depth, |pos| {
+                let other = self.get(pos);
+                other.tile == tile && other.is_same_team_as(unit)
+            })
+            .is_some()
+        }
+    }
+
+    // Checks that the position indices agree with the grid, that every unit
+    // belongs to a player and that hp and armor stay within their caps. Only
+    // meaningful between generations, while no phase is staging writes.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        for (i, &unit) in self.front.iter().enumerate() {
+            if unit.is_empty() {
+                continue;
+            }
+            let pos = self.config.position(i);
+
+            if !self.teams.get(&unit.team).is_some_and(|set| set.contains(&pos)) {
+                return Err(format!("{:?} at {:?} is missing from its team's positions", unit.tile, pos));
+            }
+            if !self.types.get(&unit.tile).is_some_and(|set| set.contains(&pos)) {
+                return Err(format!("{:?} at {:?} is missing from its type's positions", unit.tile, pos));
+            }
+            if !self.players.contains_key(&unit.team) {
+                return Err(format!("{:?} at {:?} belongs to unknown team {}", unit.tile, pos, unit.team));
+            }
+            if unit.hp == 0 || unit.hp > self.config.base_hp(unit.tile) {
+                return Err(format!("{:?} at {:?} has {} hp", unit.tile, pos, unit.hp));
+            }
+            if unit.am > self.config.armor_cap(unit.tile) {
+                return Err(format!("{:?} at {:?} has {} armor", unit.tile, pos, unit.am));
+            }
+        }
+
+        for (&id, set) in &self.teams {
+            for &pos in set {
+                if !self.in_bounds(pos) {
+                    return Err(format!("Team {} has a cell out of bounds at {:?}", id, pos));
+                }
+                let unit = self.get(pos);
+                if unit.is_empty() || unit.team != id {
+                    return Err(format!("Team {} has a ghost cell at {:?}", id, pos));
+                }
+            }
+        }
+        for (&tile, set) in &self.types {
+            for &pos in set {
+                if !self.in_bounds(pos) {
+                    return Err(format!("{:?} has a cell out of bounds at {:?}", tile, pos));
+                }
+                if tile == TileType::EMPTY || self.get(pos).tile != tile {
+                    return Err(format!("{:?} has a ghost cell at {:?}", tile, pos));
+                }
+            }
         }
+
+        for &id in self.players.keys() {
+            if !self.team_indices.contains_key(&id) || !self.stats.contains_key(&id) {
+                return Err(format!("Player {} is not fully registered", id));
+            }
+            let queens = self
+                .types
+                .get(&TileType::QUEEN)
+                .map_or(0, |set| set.iter().filter(|&&pos| self.get(pos).team == id).count());
+            if queens > 1 {
+                return Err(format!("Player {} has {} queens", id, queens));
+            }
+        }
+
+        Ok(())
     }
 
     pub fn get_leaderboard(&self) -> Vec<LeaderboardEntry> {
         let mut teams = self.teams.iter().collect::<Vec<_>>();
-        teams.sort_unstable_by_key(|(_, set)| set.len());
+        teams.sort_unstable_by_key(|&(&id, set)| (std::cmp::Reverse(set.len()), id));
 
         teams.iter().take(5).map(|(&id, set)| {
             LeaderboardEntry {
@@ -496,12 +1293,58 @@ impl Board {
         }).collect::<Vec<_>>()
     }
 
+    // Multi-source flood fill. Every source starts out with a budget that one
+    // step costs one of, and steps only go into passable cells; a diagonal
+    // step also needs one of the two cells beside it to be passable, so a
+    // diagonal line of blocked cells can not be slipped through. Returns the
+    // most budget left on arrival at each cell reached.
+    fn flood<F>(config: &GameConfig, sources: &[(Position, u16)], passable: F) -> HashMap<Position, u16>
+    where
+        F: Fn(Position) -> bool,
+    {
+        let mut best = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for &(position, budget) in sources {
+            if best.get(&position).is_none_or(|&left| budget > left) {
+                best.insert(position, budget);
+                heap.push((budget, position));
+            }
+        }
+
+        while let Some((budget, position)) = heap.pop() {
+            if budget == 0 || best[&position] > budget {
+                continue;
+            }
+
+            for &(dx, dy) in constants::ALL_OFFSETS.iter() {
+                let next = match config.adj_position(position, (dx, dy)) {
+                    Some(next) if passable(next) => next,
+                    _ => continue,
+                };
+                if dx != 0 && dy != 0 {
+                    let open = |offset| config.adj_position(position, offset).is_some_and(&passable);
+                    if !open((dx, 0)) && !open((0, dy)) {
+                        continue;
+                    }
+                }
+                if best.get(&next).is_none_or(|&left| budget - 1 > left) {
+                    best.insert(next, budget - 1);
+                    heap.push((budget - 1, next));
+                }
+            }
+        }
+
+        best
+    }
+
+    // Directions are shuffled per step when an RNG is given, so that equidistant
+    // matches are picked at random.
     #[inline]
     fn bfs<F>(
-        &self,
+        config: &GameConfig,
+        mut rng: Option<&mut ChaCha8Rng>,
         position: Position,
         max_depth: u16,
-        randomized: bool,
         predicate: F,
     ) -> Option<Position>
     where
@@ -515,48 +1358,114 @@ impl Board {
         while let Some((position, depth)) = queue.pop_front() {
             if predicate(position) {
                 return Some(position);
-            } else if (depth >= max_depth) {
+            } else if depth >= max_depth {
                 continue;
             }
 
             let mut dirs = constants::ALL_OFFSETS;
-            if randomized {
-                let mut rng = rand::thread_rng();
-                dirs[0..4].shuffle(&mut rng);
-                dirs[4..8].shuffle(&mut rng);
+            if let Some(rng) = rng.as_mut() {
+                dirs[0..4].shuffle(rng);
+                dirs[4..8].shuffle(rng);
             }
 
             for &offset in dirs.iter() {
-                self.adj_position(position, offset).map(|p| {
-                    if !seen.contains(&p) {
+                if let Some(p) = config.adj_position(position, offset) {
+                    if seen.insert(p) {
                         queue.push_back((p, depth + 1));
-                        seen.insert(p);
                     }
-                });
+                }
             }
         }
 
         None
     }
 
+    // Everything that changed since the previous call. A mobile unit that
+    // vanished from one cell and showed up unchanged in an adjacent one is
+    // reported as a move.
+    pub fn take_changes(&mut self) -> Vec<CellChange> {
+        let (config, front) = (&self.config, &self.front);
+        let mut touched = self
+            .touched
+            .drain()
+            .map(|(pos, old)| (pos, old, front[config.index(pos)]))
+            .filter(|&(_, old, new)| old != new)
+            .collect::<Vec<_>>();
+        touched.sort_unstable_by_key(|&(pos, _, _)| pos);
+
+        let same_unit = |a: Unit, b: Unit| a.tile == b.tile && a.team == b.team;
+        let mut added = touched
+            .iter()
+            .filter(|&&(_, old, new)| new.is_some() && !same_unit(old, new))
+            .map(|&(pos, _, new)| (pos, new))
+            .collect::<HashMap<_, _>>();
+
+        let mut changes = Vec::new();
+        for &(pos, old, new) in &touched {
+            if old.is_some() && !same_unit(old, new) {
+                let mobile = self.config.is_mobile(old.tile);
+                let moved_to = constants::ALL_OFFSETS
+                    .iter()
+                    .filter_map(|&offset| self.config.adj_position(pos, offset))
+                    .find(|to| {
+                        added.get(to).is_some_and(|&unit| {
+                            mobile
+                                && same_unit(old, unit)
+                                && unit.target_pos == old.target_pos
+                                && unit.stance == old.stance
+                        })
+                    });
+
+                if let Some(to) = moved_to {
+                    let unit = self.view(added.remove(&to).unwrap());
+                    changes.push(CellChange::MOVE { from: pos, to, unit });
+                } else if new.is_empty() {
+                    changes.push(CellChange::REMOVE { position: pos });
+                }
+            } else if old.is_some() && new.is_some() {
+                changes.push(CellChange::UPDATE {
+                    position: pos,
+                    hp: new.hp,
+                    am: new.am,
+                });
+            }
+        }
+
+        let mut added = added.into_iter().collect::<Vec<_>>();
+        added.sort_unstable_by_key(|&(pos, _)| pos);
+        changes.extend(
+            added
+                .into_iter()
+                .map(|(position, unit)| CellChange::ADD {
+                    position,
+                    unit: self.view(unit),
+                }),
+        );
+        changes
+    }
+
     pub fn get_window(
         &self,
         x_origin: usize,
         y_origin: usize,
         x_size: usize,
         y_size: usize,
-    ) -> Vec<Vec<Unit>> {
-        let x_min = x_origin.min(constants::X_SIZE);
-        let x_max = (x_origin + x_size).min(constants::X_SIZE);
-        let y_min = y_origin.min(constants::Y_SIZE);
-        let y_max = (y_origin + y_size).min(constants::Y_SIZE);
+    ) -> Vec<Vec<UnitView>> {
+        // Origins and sizes come straight from clients, so they may add up to
+        // more than fits. Clamped, the max never ends up below the min.
+        let x_min = x_origin.min(self.config.x_size);
+        let x_max = x_origin.saturating_add(x_size).min(self.config.x_size);
+        let y_min = y_origin.min(self.config.y_size);
+        let y_max = y_origin.saturating_add(y_size).min(self.config.y_size);
         let mut vec = Vec::with_capacity(y_max - y_min);
         for y in y_min..y_max {
-            let mut inner_vec = Vec::with_capacity(x_max - x_min);
-            for x in x_min..x_max {
-                inner_vec.push(self.get(Position::new(x, y)))
-            }
-            vec.push(inner_vec);
+            let row = self.config.index(Position::new(0, y));
+            vec.push(
+                self.front[row + x_min..row + x_max]
+                    .iter()
+                    .map(|&unit| self.view(unit))
+                    .collect(),
+            );
         }
         vec
     }
@@ -570,3 +1479,372 @@ Spawners spawn
 All units that want to move will attempt to move 1 tile towards their location
 Attacking units deal damage. If both are in range, both deal damage.
 */
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use proptest::prelude::*;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{Board, Unit};
+    use crate::config::GameConfig;
+    use crate::data::{CellChange, Position, TileType, UnitView, Viewport};
+    use crate::scenario::{self, Scenario};
+    use crate::snapshot::Snapshot;
+
+    const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+    const EXPECTED: &str = ".expected.toml";
+
+    // Every scenario in `tests/golden` is run for its number of generations and
+    // the board it ends up with is compared against the exported scenario next
+    // to it. Run with `UPDATE_GOLDEN=1` to rewrite the expected boards after an
+    // intended rule change, then review the diff.
+    #[test]
+    fn golden_boards() {
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut paths = fs::read_dir(GOLDEN_DIR)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.ends_with(".toml") && !name.ends_with(EXPECTED)
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert!(!paths.is_empty(), "no golden scenarios in {}", GOLDEN_DIR);
+
+        let mut failed = Vec::new();
+        for path in &paths {
+            let scenario = Scenario::load(path).unwrap();
+            let mut board = scenario.build().unwrap();
+            for _ in 0..scenario.generations {
+                board.next_with(&scenario.rules);
+            }
+            let actual = scenario::export(&board).unwrap();
+
+            let expected_path = path.with_extension("").with_extension(&EXPECTED[1..]);
+            if update {
+                fs::write(&expected_path, &actual).unwrap();
+                continue;
+            }
+            match fs::read_to_string(&expected_path) {
+                Ok(expected) if expected == actual => {}
+                Ok(expected) => failed.push(format!(
+                    "{} does not match {}:\n--- expected\n{}\n--- actual\n{}",
+                    path.display(),
+                    expected_path.display(),
+                    expected,
+                    actual
+                )),
+                Err(err) => failed.push(format!("{}: {}", expected_path.display(), err)),
+            }
+        }
+
+        assert!(failed.is_empty(), "{}", failed.join("\n"));
+    }
+
+    // Bots coming and going in the open world must not grow the palette for
+    // good, and a team still on the board keeps its index.
+    #[test]
+    fn gone_teams_free_their_index() {
+        let config = Arc::new(GameConfig {
+            x_size: 40,
+            y_size: 40,
+            ..GameConfig::default()
+        });
+        let mut board = Board::with_seed(config, 3);
+        let stays = Uuid::from_u128(1);
+        board.spawn_player(stays).unwrap();
+
+        for n in 2..100 {
+            let id = Uuid::from_u128(n);
+            board.spawn_player(id).unwrap();
+            assert_eq!(board.team_index(id), 2);
+            board.next();
+            board.remove_player(id);
+        }
+
+        assert_eq!(board.team_index(stays), 1);
+        assert_eq!(board.palette().len(), 2);
+        assert!(board.stats(Uuid::from_u128(2)).is_none());
+        board.check_invariants().unwrap();
+    }
+
+    // Only the teams still on the board need a symbol, however high their
+    // palette index has climbed.
+    #[test]
+    fn exports_after_many_teams() {
+        let config = Arc::new(GameConfig {
+            x_size: 120,
+            y_size: 120,
+            ..GameConfig::default()
+        });
+        let mut board = Board::with_seed(config, 5);
+        let ids = (1..=70).map(Uuid::from_u128).collect::<Vec<_>>();
+        for &id in &ids {
+            board.spawn_player(id).unwrap();
+        }
+        for &id in &ids[1..69] {
+            board.remove_player(id);
+        }
+        assert_eq!(board.team_index(ids[69]), 70);
+
+        let exported = scenario::export(&board).unwrap();
+        let restored = Scenario::parse(&exported).unwrap().build().unwrap();
+        assert_eq!(restored.units().count(), board.units().count());
+    }
+
+    // Windows are asked for by clients, who can send any numbers at all.
+    #[test]
+    fn windows_never_overflow() {
+        let board = Board::with_seed(Arc::new(GameConfig::default()), 1);
+        let side = board.config().x_size;
+
+        assert!(board.get_window(usize::MAX, usize::MAX, usize::MAX, usize::MAX).is_empty());
+        let window = board.get_window(side - 2, 0, usize::MAX, 3);
+        assert_eq!(window.len(), 3);
+        assert!(window.iter().all(|row| row.len() == 2));
+    }
+
+    // Applies a delta the way clients do: every vacated cell is cleared
+    // before anything is drawn, so the order within a delta does not matter.
+    fn apply_delta(board: &Board, window: &mut [Vec<UnitView>], area: Viewport, changes: &[CellChange]) {
+        let empty = board.view(Unit::EMPTY);
+        let at = |position: Position| (position.y - area.y_origin, position.x - area.x_origin);
+        for change in changes {
+            if let CellChange::REMOVE { position } | CellChange::MOVE { from: position, .. } = *change {
+                let (y, x) = at(position);
+                window[y][x] = empty;
+            }
+        }
+        for change in changes {
+            match *change {
+                CellChange::ADD { position, unit } | CellChange::MOVE { to: position, unit, .. } => {
+                    let (y, x) = at(position);
+                    window[y][x] = unit;
+                }
+                CellChange::UPDATE { position, hp, am } => {
+                    let (y, x) = at(position);
+                    window[y][x].hp = hp;
+                    window[y][x].am = am;
+                }
+                CellChange::REMOVE { .. } => {}
+            }
+        }
+    }
+
+    // A client that keeps applying the deltas for its viewport has to end up
+    // with the same window it would get by asking for it again. The top row
+    // walks in a chain, the bottom pair swaps places, and both cross the
+    // edges of the smaller viewports on the way.
+    #[test]
+    fn deltas_rebuild_windows() {
+        let mut overrides = String::new();
+        for &(x, y, to_x, to_y) in &[(2, 1, 14, 1), (3, 1, 14, 1), (4, 1, 14, 1), (10, 4, 13, 4), (11, 4, 8, 4)] {
+            overrides += &format!(
+                "[[overrides]]\nx = {}\ny = {}\nhp = 50\nrally = {{ x = {}, y = {} }}\n",
+                x, y, to_x, to_y
+            );
+        }
+        let grid = [
+            "................................",
+            "....A1G1A1......................",
+            "................................",
+            "................................",
+            "....................A1G1........",
+            "................................",
+        ];
+        let scenario = Scenario::parse(&format!(
+            "seed = 3\nrules = [\"COMBAT\"]\ngrid = \"{}\"\nlegend = {{ \"1\" = \"red\" }}\n{}",
+            grid.join("\\n"),
+            overrides
+        ))
+        .unwrap();
+        let mut board = scenario.build().unwrap();
+        board.take_changes();
+
+        let area = |x_origin, y_origin, x_size, y_size| Viewport { x_origin, y_origin, x_size, y_size };
+        let areas = [area(0, 0, 16, 6), area(0, 0, 8, 3), area(6, 1, 4, 1), area(11, 3, 5, 3)];
+        let (mut chained, mut swapped, mut crossed) = (false, false, false);
+        for _ in 0..12 {
+            let mut windows = areas
+                .iter()
+                .map(|a| board.get_window(a.x_origin, a.y_origin, a.x_size, a.y_size))
+                .collect::<Vec<_>>();
+            board.next();
+            let changes = board.take_changes();
+
+            let moves = changes
+                .iter()
+                .filter_map(|change| match *change {
+                    CellChange::MOVE { from, to, .. } => Some((from, to)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            for &(from, to) in &moves {
+                chained |= moves.iter().any(|&(next, _)| next == to) && !moves.contains(&(to, from));
+                swapped |= moves.contains(&(to, from));
+                crossed |= areas.iter().any(|a| a.contains(from) != a.contains(to));
+            }
+
+            for (window, &area) in windows.iter_mut().zip(&areas) {
+                apply_delta(&board, window, area, &area.filter(&changes));
+                assert_eq!(
+                    *window,
+                    board.get_window(area.x_origin, area.y_origin, area.x_size, area.y_size),
+                    "{:?} after generation {}",
+                    area,
+                    board.generation()
+                );
+            }
+        }
+        assert!(chained && swapped && crossed, "chained {}, swapped {}, crossed {}", chained, swapped, crossed);
+    }
+
+    // Export has to carry the rules along, nested tables included, or a
+    // reloaded board plays out differently.
+    #[test]
+    fn exports_keep_game_settings() {
+        let scenario = Scenario::parse(
+            r#"
+            grid = "Q1"
+            legend = { "1" = "red" }
+
+            [game]
+            init_erg = 300
+            path_budget = 7
+
+            [[game.starvation]]
+            within = 4
+            drain = 2
+
+            [game.income]
+            base = 1
+            kill_bounty = 0
+            "#,
+        )
+        .unwrap();
+        let board = scenario.build().unwrap();
+
+        let exported = Scenario::parse(&scenario::export(&board).unwrap()).unwrap();
+        assert_eq!(exported.game, scenario.game);
+        assert_eq!(exported.build().unwrap().config(), board.config());
+    }
+
+    // A board whose RNG has not drawn yet, like a lobby match nobody has
+    // joined, still has to snapshot and pick up the same stream on restore.
+    #[test]
+    fn snapshots_of_fresh_boards() {
+        let config = Arc::new(GameConfig::default());
+        let mut board = Board::with_seed(Arc::clone(&config), 9);
+        let mut restored = Snapshot::capture(&board).restore(config).unwrap();
+
+        for board in [&mut board, &mut restored] {
+            board.spawn_player(Uuid::from_u128(1));
+            board.next();
+        }
+        assert_eq!(scenario::export(&restored).unwrap(), scenario::export(&board).unwrap());
+    }
+
+    // Players join out of id order and one leaves before the snapshot, so a
+    // restore that re-adds them by id would hand out other palette indices.
+    #[test]
+    fn snapshots_keep_palette_and_stats() {
+        let config = Arc::new(GameConfig {
+            x_size: 40,
+            y_size: 40,
+            ..GameConfig::default()
+        });
+        let mut board = Board::with_seed(Arc::clone(&config), 7);
+        let ids = [3, 1, 2].map(Uuid::from_u128);
+        for &id in &ids {
+            board.spawn_player(id);
+        }
+        for _ in 0..5 {
+            board.next();
+        }
+        board.remove_player(ids[1]);
+
+        let mut restored = Snapshot::capture(&board).restore(config).unwrap();
+        restored.check_invariants().unwrap();
+        assert_eq!(restored.palette(), board.palette());
+        for &id in &ids {
+            assert_eq!(format!("{:?}", restored.standing(id)), format!("{:?}", board.standing(id)));
+        }
+
+        for _ in 0..5 {
+            board.next();
+            restored.next();
+        }
+        assert_eq!(scenario::export(&restored).unwrap(), scenario::export(&board).unwrap());
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Join(u8),
+        Leave(u8),
+        Put(u8, usize, usize, TileType),
+        Tick,
+    }
+
+    const PLAYERS: u8 = 4;
+    const SIDE: usize = 24;
+
+    fn tile() -> impl Strategy<Value = TileType> {
+        prop_oneof![
+            Just(TileType::BASE),
+            Just(TileType::SPAWNER),
+            Just(TileType::FEEDER),
+            Just(TileType::BOLSTER),
+            Just(TileType::GUARD),
+            Just(TileType::ATTACK),
+            Just(TileType::QUEEN),
+        ]
+    }
+
+    // Puts reach a little past the edges, to exercise the bounds checks.
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            1 => (0..PLAYERS).prop_map(Op::Join),
+            1 => (0..PLAYERS).prop_map(Op::Leave),
+            6 => (0..PLAYERS, 0..SIDE + 4, 0..SIDE + 4, tile()).prop_map(|(p, x, y, t)| Op::Put(p, x, y, t)),
+            4 => Just(Op::Tick),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn indices_stay_in_sync(seed in any::<u64>(), ops in prop::collection::vec(op(), 1..120)) {
+            let config = GameConfig {
+                x_size: SIDE,
+                y_size: SIDE,
+                init_erg: 5000,
+                ..GameConfig::default()
+            };
+            let mut board = Board::with_seed(Arc::new(config), seed);
+            let id = |player: u8| Uuid::from_u128(player as u128 + 1);
+
+            for op in ops {
+                match op {
+                    Op::Join(player) => {
+                        if board.get_player(id(player)).is_none() {
+                            board.spawn_player(id(player));
+                        }
+                    }
+                    Op::Leave(player) => board.remove_player(id(player)),
+                    Op::Put(player, x, y, tile) => {
+                        let _ = board.put(id(player), Position::new(x, y), tile);
+                    }
+                    Op::Tick => board.next(),
+                }
+                if let Err(err) = board.check_invariants() {
+                    return Err(TestCaseError::fail(format!("after {:?}: {}", op, err)));
+                }
+            }
+        }
+    }
+}
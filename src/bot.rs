@@ -0,0 +1,187 @@
+use uuid::Uuid;
+
+use crate::board::Board;
+use crate::data::{Position, TileType, UnitView};
+use crate::tiles::TileSet;
+
+// What a bot gets to see each turn: the same kind of window a client
+// subscribes to, centred on its queen, plus its energy and the generation.
+pub struct BotView<'a> {
+    pub team: u16,
+    pub queen: Position,
+    pub energy: u32,
+    pub generation: usize,
+    pub x_origin: usize,
+    pub y_origin: usize,
+    pub window: Vec<Vec<UnitView>>,
+    pub tiles: &'a TileSet,
+}
+
+impl<'a> BotView<'a> {
+    // `None` if the player has no queen left.
+    pub fn new(board: &'a Board, id: Uuid, radius: usize) -> Option<Self> {
+        let queen = board.queen_position(id)?;
+        let x_origin = queen.x.saturating_sub(radius);
+        let y_origin = queen.y.saturating_sub(radius);
+        let size = radius * 2 + 1;
+
+        Some(Self {
+            team: board.team_index(id),
+            queen,
+            energy: board.get_player(id).map_or(0, |player| player.energy),
+            generation: board.generation(),
+            x_origin,
+            y_origin,
+            window: board.get_window(x_origin, y_origin, size, size),
+            tiles: &board.config().tiles,
+        })
+    }
+
+    pub fn get(&self, Position { x, y }: Position) -> Option<UnitView> {
+        let row = self.window.get(y.checked_sub(self.y_origin)?)?;
+        row.get(x.checked_sub(self.x_origin)?).copied()
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = (Position, UnitView)> + '_ {
+        self.window.iter().enumerate().flat_map(move |(dy, row)| {
+            row.iter()
+                .enumerate()
+                .map(move |(dx, &unit)| (Position::new(self.x_origin + dx, self.y_origin + dy), unit))
+        })
+    }
+
+    pub fn count(&self, tile: TileType) -> usize {
+        self.cells()
+            .filter(|(_, unit)| unit.team == self.team && unit.tile == tile)
+            .count()
+    }
+
+    pub fn can_afford(&self, tile: TileType) -> bool {
+        self.tiles
+            .get(tile)
+            .and_then(|def| def.cost)
+            .is_some_and(|cost| cost < self.energy)
+    }
+
+    // Free cells next to one of our own, closest to `target` first.
+    pub fn frontier(&self, target: Position) -> Vec<Position> {
+        let mut cells = self
+            .cells()
+            .filter(|(_, unit)| unit.tile == TileType::EMPTY)
+            .map(|(pos, _)| pos)
+            .filter(|&pos| {
+                neighbours(pos).any(|near| self.get(near).is_some_and(|unit| unit.team == self.team))
+            })
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|&pos| (pos.distance(target), pos));
+        cells
+    }
+
+    pub fn nearest_enemy(&self) -> Option<Position> {
+        self.cells()
+            .filter(|(_, unit)| unit.team != 0 && unit.team != self.team)
+            .map(|(pos, _)| pos)
+            .min_by_key(|&pos| (pos.distance(self.queen), pos))
+    }
+}
+
+fn neighbours(Position { x, y }: Position) -> impl Iterator<Item = Position> {
+    (-1isize..=1)
+        .flat_map(move |dy| (-1isize..=1).map(move |dx| (dx, dy)))
+        .filter(|&offset| offset != (0, 0))
+        .filter_map(move |(dx, dy)| {
+            let x = (x as isize).checked_add(dx).filter(|&x| x >= 0)?;
+            let y = (y as isize).checked_add(dy).filter(|&y| y >= 0)?;
+            Some(Position::new(x as usize, y as usize))
+        })
+}
+
+pub enum Action {
+    Put { position: Position, tile: TileType },
+}
+
+// Plays a colony in place of a client. Asked for at most one action per
+// turn, which goes through the same checks as a client's PUT.
+pub trait Bot: Send {
+    fn think(&mut self, view: &BotView) -> Option<Action>;
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Strategy {
+    TURTLE,
+    RUSH,
+    ECONOMY,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 3] = [Strategy::TURTLE, Strategy::RUSH, Strategy::ECONOMY];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::TURTLE => "turtle",
+            Strategy::RUSH => "rush",
+            Strategy::ECONOMY => "economy",
+        }
+    }
+
+    pub fn bot(self) -> Box<dyn Bot> {
+        match self {
+            Strategy::TURTLE => Box::new(Turtle),
+            Strategy::RUSH => Box::new(Rush),
+            Strategy::ECONOMY => Box::new(Economy),
+        }
+    }
+}
+
+// Places `tile` on the frontier cell closest to `target`, if it can pay.
+fn build(view: &BotView, tile: TileType, target: Position) -> Option<Action> {
+    if !view.can_afford(tile) {
+        return None;
+    }
+    view.frontier(target)
+        .first()
+        .map(|&position| Action::Put { position, tile })
+}
+
+// Keeps fed and walls its queen in with guards.
+pub struct Turtle;
+
+impl Bot for Turtle {
+    fn think(&mut self, view: &BotView) -> Option<Action> {
+        if view.count(TileType::FEEDER) == 0 {
+            return build(view, TileType::FEEDER, view.queen);
+        }
+        if view.count(TileType::BOLSTER) == 0 {
+            return build(view, TileType::BOLSTER, view.queen);
+        }
+        build(view, TileType::GUARD, view.queen)
+    }
+}
+
+// Sends attackers at whoever is closest.
+pub struct Rush;
+
+impl Bot for Rush {
+    fn think(&mut self, view: &BotView) -> Option<Action> {
+        if view.count(TileType::FEEDER) == 0 {
+            return build(view, TileType::FEEDER, view.queen);
+        }
+        let target = view.nearest_enemy().unwrap_or(view.queen);
+        build(view, TileType::ATTACK, target)
+    }
+}
+
+// Grows through spawners and keeps them fed.
+pub struct Economy;
+
+impl Bot for Economy {
+    fn think(&mut self, view: &BotView) -> Option<Action> {
+        let feeders = view.count(TileType::FEEDER);
+        let spawners = view.count(TileType::SPAWNER);
+        if feeders == 0 || spawners > feeders * 2 {
+            return build(view, TileType::FEEDER, view.queen);
+        }
+        build(view, TileType::SPAWNER, view.queen)
+    }
+}
@@ -0,0 +1,343 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bot::Strategy;
+use crate::constants;
+use crate::data::{Position, TileType};
+use crate::tiles::{TileDef, TileSet};
+use crate::win::WinCondition;
+
+// Runtime settings, read from a TOML file and overridden from the command
+// line. Anything left out falls back to the values in `constants`.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Tile definitions to use instead of the built-in ones.
+    pub tiles: Option<String>,
+    pub server: ServerConfig,
+    pub lobby: LobbyConfig,
+    pub phases: PhaseConfig,
+    pub bots: BotConfig,
+    pub game: GameConfig,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub seed: Option<u64>,
+    pub tick_ms: u64,
+    pub leaderboard_interval: usize,
+    pub snapshot_interval: usize,
+    pub snapshot_dir: String,
+    pub replay_dir: String,
+    pub reconnect_grace: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: constants::BIND_ADDR.to_owned(),
+            seed: None,
+            tick_ms: constants::TICK_MS,
+            leaderboard_interval: constants::LEADERBOARD_INTERVAL,
+            snapshot_interval: constants::SNAPSHOT_INTERVAL,
+            snapshot_dir: constants::SNAPSHOT_DIR.to_owned(),
+            replay_dir: constants::REPLAY_DIR.to_owned(),
+            reconnect_grace: constants::RECONNECT_GRACE,
+        }
+    }
+}
+
+// How queued players are grouped into matches. Matches run next to the open
+// world, which anyone can drop into at any time.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LobbyConfig {
+    pub open_world: bool,
+    pub players_per_match: usize,
+    // Match boards are square, with about this many cells per player.
+    pub cells_per_player: usize,
+    pub max_matches: usize,
+    // Checked in order after every generation.
+    pub win: Vec<WinCondition>,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        Self {
+            open_world: true,
+            players_per_match: constants::PLAYERS_PER_MATCH,
+            cells_per_player: constants::CELLS_PER_PLAYER,
+            max_matches: constants::MAX_MATCHES,
+            win: vec![WinCondition::LAST_QUEEN_STANDING],
+        }
+    }
+}
+
+impl LobbyConfig {
+    pub fn board_side(&self, players: usize) -> usize {
+        let cells = (players * self.cells_per_player) as f64;
+        (cells.sqrt().ceil() as usize).max(constants::MIN_MATCH_SIDE)
+    }
+}
+
+// Matches with phases alternate between placement windows, where the board is
+// paused and players build, and a number of generations where it evolves on
+// its own and placements are rejected.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PhaseConfig {
+    pub lobby: bool,
+    pub open_world: bool,
+    pub build_ms: u64,
+    pub evolve_generations: usize,
+}
+
+impl Default for PhaseConfig {
+    fn default() -> Self {
+        Self {
+            lobby: true,
+            open_world: false,
+            build_ms: constants::BUILD_MS,
+            evolve_generations: constants::EVOLVE_GENERATIONS,
+        }
+    }
+}
+
+impl PhaseConfig {
+    pub fn applies_to(&self, open_world: bool) -> bool {
+        if open_world {
+            self.open_world
+        } else {
+            self.lobby
+        }
+    }
+}
+
+// Server-run players that keep boards from feeling empty.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BotConfig {
+    // Bots are added to the open world until it has this many players, and
+    // leave again as people join.
+    pub open_world: usize,
+    // Whether a queued player gets a match straight away, with bots taking
+    // the seats nobody is waiting for.
+    pub fill_matches: bool,
+    // Handed out in turn to new bots.
+    pub strategies: Vec<Strategy>,
+    // How far around its queen a bot can see.
+    pub view_radius: usize,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            open_world: 0,
+            fill_matches: false,
+            strategies: Strategy::ALL.to_vec(),
+            view_radius: constants::BOT_VIEW_RADIUS,
+        }
+    }
+}
+
+// The rules a board is simulated with. Recorded in replays so they can be
+// re-run under the settings they were played with.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    pub x_size: usize,
+    pub y_size: usize,
+    pub init_erg: u32,
+    // Checked in order, the first tier that reaches a unit applies.
+    pub starvation: Vec<StarvationTier>,
+    // Cells pathfinding may expand per generation, see `constants`.
+    pub path_budget: usize,
+    pub income: IncomeConfig,
+    pub tiles: TileSet,
+}
+
+// Energy players earn on top of what their tiles make, see `constants`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IncomeConfig {
+    // Paid every generation to every player with a queen.
+    pub base: u32,
+    // One energy a generation for every this many cells held, 0 for none.
+    pub cells_per_erg: usize,
+    // Paid to whoever is credited with a kill.
+    pub kill_bounty: u32,
+}
+
+impl Default for IncomeConfig {
+    fn default() -> Self {
+        Self {
+            base: constants::INCOME_BASE,
+            cells_per_erg: constants::INCOME_CELLS_PER_ERG,
+            kill_bounty: constants::KILL_BOUNTY,
+        }
+    }
+}
+
+// How hard a unit out of every friendly feed range starves, by how far it is
+// from the nearest friendly queen or feeder.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StarvationTier {
+    // Reaches units up to this far, or any unit if left out.
+    #[serde(default)]
+    pub within: Option<u16>,
+    pub drain: u32,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            x_size: constants::X_SIZE,
+            y_size: constants::Y_SIZE,
+            init_erg: constants::INIT_ERG,
+            starvation: vec![
+                StarvationTier {
+                    within: Some(constants::STARVATION_NEAR),
+                    drain: constants::STARVATION_NEAR_DRAIN,
+                },
+                StarvationTier {
+                    within: None,
+                    drain: constants::STARVATION_FAR_DRAIN,
+                },
+            ],
+            path_budget: constants::PATH_BUDGET,
+            income: IncomeConfig::default(),
+            tiles: TileSet::default(),
+        }
+    }
+}
+
+impl GameConfig {
+    pub fn tile(&self, tile: TileType) -> Option<&TileDef> {
+        self.tiles.get(tile)
+    }
+
+    pub fn base_hp(&self, tile: TileType) -> u32 {
+        self.tile(tile).map_or(0, |def| def.base_hp)
+    }
+
+    pub fn armor_cap(&self, tile: TileType) -> u32 {
+        self.tile(tile).map_or(0, |def| def.armor_cap)
+    }
+
+    // Fighters are the only tiles that move, and the only ones that take
+    // orders.
+    pub fn is_mobile(&self, tile: TileType) -> bool {
+        self.tile(tile).is_some_and(|def| def.aggro_radius.is_some())
+    }
+
+    // `None` for tiles that can not be bought.
+    pub fn cost(&self, tile: TileType) -> Option<u32> {
+        self.tile(tile).and_then(|def| def.cost)
+    }
+
+    // How far to look for food when working out which tier a unit is in.
+    pub fn starvation_reach(&self) -> u16 {
+        self.starvation.iter().filter_map(|tier| tier.within).max().unwrap_or(0)
+    }
+
+    // hp lost by a starving unit whose nearest friendly food source is
+    // `distance` away, if there is one in reach at all.
+    pub fn starvation_drain(&self, distance: Option<u16>) -> u32 {
+        self.starvation
+            .iter()
+            .find(|tier| match (tier.within, distance) {
+                (None, _) => true,
+                (Some(within), Some(distance)) => distance <= within,
+                (Some(_), None) => false,
+            })
+            .map_or(0, |tier| tier.drain)
+    }
+
+    // Boards smaller than this have no room to spawn a queen away from the
+    // edges, and the spawning code assumes there is.
+    pub fn check(&self) -> io::Result<()> {
+        if self.x_size < constants::MIN_MATCH_SIDE || self.y_size < constants::MIN_MATCH_SIDE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The board is {}x{}, it has to be at least {} on each side",
+                    self.x_size,
+                    self.y_size,
+                    constants::MIN_MATCH_SIDE
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn in_bounds(&self, Position { x, y }: Position) -> bool {
+        x < self.x_size && y < self.y_size
+    }
+
+    #[inline]
+    pub fn index(&self, Position { x, y }: Position) -> usize {
+        y * self.x_size + x
+    }
+
+    #[inline]
+    pub fn position(&self, index: usize) -> Position {
+        Position::new(index % self.x_size, index / self.x_size)
+    }
+
+    pub fn adj_position(
+        &self,
+        Position { x, y }: Position,
+        (dx, dy): (isize, isize),
+    ) -> Option<Position> {
+        if dx < 0 && x == 0
+            || dy < 0 && y == 0
+            || dx > 0 && x == self.x_size - 1
+            || dy > 0 && y == self.y_size - 1
+        {
+            None
+        } else {
+            let x = if dx < 0 {
+                x - -dx as usize
+            } else {
+                x + dx as usize
+            };
+
+            let y = if dy < 0 {
+                y - -dy as usize
+            } else {
+                y + dy as usize
+            };
+
+            Some(Position::new(x, y))
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut config: Self = toml::from_str(&text)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if let Some(tiles) = &config.tiles {
+            config.game.tiles = TileSet::load(tiles)?;
+        }
+        config.game.check()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example says anything left out keeps its default, so what it does
+    // spell out has to be the defaults too.
+    #[test]
+    fn example_matches_defaults() {
+        let config = Config::load("config.example.toml").unwrap();
+        assert_eq!(config, Config::default());
+    }
+}
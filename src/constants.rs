@@ -14,38 +14,43 @@ pub const Y_SIZE: usize = 500;
 
 pub const INIT_ERG: u32 = 2020;
 
-pub const MAX_HP: u32 = 8;
-pub const MAX_AM: u32 = 8;
-pub const ATK_DMG: u32 = 4;
-pub const GRD_DMG: u32 = 3;
-
-use crate::data::TileType;
-use std::u32;
-
-impl TileType {
-    pub fn get_base_hp(self) -> u32 {
-        match self {
-            TileType::EMPTY => 0,
-            TileType::BASE => 3,
-            TileType::SPAWNER => 3,
-            TileType::FEEDER => 4,
-            TileType::BOLSTER => 1,
-            TileType::GUARD => 10,
-            TileType::ATTACK => 6,
-            TileType::QUEEN => 10,
-        }
-    }
-
-    pub fn get_cost(self) -> u32 {
-        match self {
-            TileType::EMPTY => u32::MAX,
-            TileType::BASE => 100,
-            TileType::SPAWNER => 750,
-            TileType::FEEDER => 325,
-            TileType::BOLSTER => 500,
-            TileType::GUARD => 650,
-            TileType::ATTACK => 725,
-            TileType::QUEEN => u32::MAX,
-        }
-    }
-}
+pub const BIND_ADDR: &str = "127.0.0.1:2794";
+pub const TICK_MS: u64 = 1000;
+pub const LEADERBOARD_INTERVAL: usize = 20;
+
+pub const PLAYERS_PER_MATCH: usize = 4;
+pub const CELLS_PER_PLAYER: usize = 2500;
+pub const MAX_MATCHES: usize = 8;
+// Small enough boards can not fit a queen away from the edges.
+pub const MIN_MATCH_SIDE: usize = 20;
+
+pub const BUILD_MS: u64 = 20_000;
+pub const EVOLVE_GENERATIONS: usize = 30;
+
+pub const REPLAY_DIR: &str = "replays";
+pub const SNAPSHOT_DIR: &str = "snapshots";
+pub const SNAPSHOT_INTERVAL: usize = 30;
+
+// Generations a disconnected player's colony keeps simulating before removal.
+pub const RECONNECT_GRACE: usize = 60;
+
+pub const BOT_VIEW_RADIUS: usize = 15;
+
+// Units out of every friendly feed range lose this much hp a generation while
+// a friendly queen or feeder is close by, and more once there is none.
+pub const STARVATION_NEAR: u16 = 10;
+pub const STARVATION_NEAR_DRAIN: u32 = 1;
+pub const STARVATION_FAR_DRAIN: u32 = 3;
+
+// Cells pathfinding may expand per generation across all units, and for any
+// one unit. Units move straight at their target once the budget is spent.
+pub const PATH_BUDGET: usize = 50_000;
+pub const PATH_SEARCH_LIMIT: usize = 500;
+// How far around a taken rally point units look for a free cell to gather on.
+pub const RALLY_SPREAD: usize = 10;
+
+// Energy every living colony earns a generation, one more for every so many
+// cells it holds, and a bounty for each kill.
+pub const INCOME_BASE: u32 = 5;
+pub const INCOME_CELLS_PER_ERG: usize = 20;
+pub const KILL_BOUNTY: u32 = 10;
@@ -1,15 +1,48 @@
 use uuid::Uuid;
 
-#[derive(Copy, Clone, PartialEq, Eq, Serialize)]
+use crate::win::WinCondition;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Unit {
     pub tile: TileType,
     pub team: Uuid,
     pub hp: u32,
     pub am: u32,
-    pub target_pos: Position,
+    // The rally point a fighter heads back to when it has nothing to fight,
+    // if it has one.
+    pub target_pos: Option<Position>,
+    pub stance: Stance,
+}
+
+// How a fighter picks its fights. Aggressive ones chase anything within their
+// aggro radius, patrols only what comes within that radius of their rally
+// point, and ones holding their ground only hit what is next to them.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+pub enum Stance {
+    #[default]
+    AGGRESSIVE,
+    HOLD,
+    PATROL,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+// What clients see of a unit. Teams are referred to by their index in the
+// match palette rather than by UUID, so every cell stays a few bytes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct UnitView {
+    pub tile: TileType,
+    pub team: u16,
+    pub hp: u32,
+    pub am: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct PaletteEntry {
+    pub team: u16,
+    pub id: Uuid,
+    pub name: Option<String>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Serialize, Deserialize)]
 pub enum TileType {
     EMPTY,
     BASE,
@@ -27,6 +60,8 @@ pub enum TileType {
 pub enum Response {
     IDENTIFY {
         id: Uuid,
+        team: u16,
+        token: Uuid,
         origin: Position,
         x_size: usize,
         y_size: usize,
@@ -37,7 +72,7 @@ pub enum Response {
     FRAME {
         x_size: usize,
         y_size: usize,
-        window: Vec<Vec<Unit>>
+        window: Vec<Vec<UnitView>>
     },
     NOTICE {
         string: String,
@@ -47,7 +82,113 @@ pub enum Response {
     },
     LEADERBOARD_UPDATE {
         leaderboard: Vec<LeaderboardEntry>
-    }
+    },
+    DELTA {
+        gen: usize,
+        changes: Vec<CellChange>,
+    },
+    PALETTE {
+        teams: Vec<PaletteEntry>,
+    },
+    HELLO {
+        version: u32,
+        min_version: u32,
+        max_version: u32,
+    },
+    ERROR {
+        code: ErrorCode,
+        message: String,
+        request_id: Option<u64>,
+    },
+    QUEUED {
+        players: usize,
+        needed: usize,
+    },
+    MATCH_START {
+        match_id: u64,
+    },
+    GAME_OVER {
+        match_id: u64,
+        condition: WinCondition,
+        winner: Option<Uuid>,
+        standings: Vec<Standing>,
+    },
+    // The countdown is in milliseconds while building and in generations
+    // while evolving.
+    PHASE_CHANGE {
+        phase: Phase,
+        remaining_ms: Option<u64>,
+        remaining_generations: Option<usize>,
+    },
+    // How many units took an order.
+    ORDERED {
+        units: usize,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+pub enum Phase {
+    BUILD,
+    EVOLVE,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+pub enum ErrorCode {
+    BAD_REQUEST,
+    UNSUPPORTED_VERSION,
+    NOT_IN_GAME,
+    OUT_OF_BOUNDS,
+    OCCUPIED,
+    INSUFFICIENT_ENERGY,
+    NOT_FOR_SALE,
+    WRONG_PHASE,
+    INVALID_SESSION,
+    NO_ROOM,
+}
+
+// Any request may carry a `request_id`, which is echoed on the replies to
+// it. Binary clients have to send requests as maps for this to work.
+#[derive(Deserialize)]
+pub struct Envelope {
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Serialize)]
+pub struct Reply<'a> {
+    pub request_id: u64,
+    #[serde(flatten)]
+    pub response: &'a Response,
+}
+
+// Cells of a subscribed viewport that changed over one generation. Clients
+// first clear every `REMOVE` position and `MOVE` origin, then apply the
+// `ADD`, `UPDATE` and `MOVE` destinations, so the order within a delta
+// does not matter.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(tag = "kind")]
+pub enum CellChange {
+    ADD {
+        position: Position,
+        unit: UnitView,
+    },
+    REMOVE {
+        position: Position,
+    },
+    UPDATE {
+        position: Position,
+        hp: u32,
+        am: u32,
+    },
+    MOVE {
+        from: Position,
+        to: Position,
+        unit: UnitView,
+    },
 }
 
 #[derive(Serialize)]
@@ -56,6 +197,20 @@ pub struct LeaderboardEntry {
     pub score: usize,
 }
 
+// Where a player finished and how their game went, best first.
+#[derive(Clone, Debug, Serialize)]
+pub struct Standing {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub cells: usize,
+    pub alive: bool,
+    pub peak_cells: usize,
+    pub kills: usize,
+    pub energy_spent: u32,
+    pub energy_earned: u32,
+    pub generations_survived: usize,
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Deserialize)]
 #[serde(tag = "type")]
@@ -63,6 +218,9 @@ pub enum Request {
     NEW_PLAYER {
         username: String,
     },
+    RESUME {
+        token: Uuid,
+    },
     EXIT_GAME,
     REQUEST_FRAME {
         x_origin: usize,
@@ -73,10 +231,69 @@ pub enum Request {
     PUT {
         position: Position,
         tile: TileType,
+    },
+    SUBSCRIBE {
+        x_origin: usize,
+        y_origin: usize,
+        x_size: usize,
+        y_size: usize,
+    },
+    QUEUE {
+        username: String,
+    },
+    LEAVE_QUEUE,
+    // Orders every fighter of the player's inside `area`.
+    SET_RALLY {
+        area: Viewport,
+        position: Position,
+    },
+    SET_STANCE {
+        area: Viewport,
+        stance: Stance,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Viewport {
+    pub x_origin: usize,
+    pub y_origin: usize,
+    pub x_size: usize,
+    pub y_size: usize,
+}
+
+impl Viewport {
+    pub fn contains(&self, Position { x, y }: Position) -> bool {
+        x >= self.x_origin
+            && x - self.x_origin < self.x_size
+            && y >= self.y_origin
+            && y - self.y_origin < self.y_size
+    }
+
+    // Narrows a delta down to what this viewport can see. Moves across its
+    // edge turn into plain adds or removes.
+    pub fn filter(&self, changes: &[CellChange]) -> Vec<CellChange> {
+        changes
+            .iter()
+            .filter_map(|&change| match change {
+                CellChange::ADD { position, .. }
+                | CellChange::REMOVE { position }
+                | CellChange::UPDATE { position, .. } => {
+                    Some(change).filter(|_| self.contains(position))
+                }
+                CellChange::MOVE { from, to, unit } => {
+                    match (self.contains(from), self.contains(to)) {
+                        (true, true) => Some(change),
+                        (true, false) => Some(CellChange::REMOVE { position: from }),
+                        (false, true) => Some(CellChange::ADD { position: to, unit }),
+                        (false, false) => None,
+                    }
+                }
+            })
+            .collect()
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Serialize, Deserialize, Default)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Serialize, Deserialize, Default)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -86,4 +303,11 @@ impl Position {
     pub const fn new(x: usize, y: usize) -> Self {
         Self { x, y }
     }
+
+    // Steps between two cells when diagonal moves count as one.
+    pub fn distance(self, other: Position) -> usize {
+        let dx = (self.x as isize - other.x as isize).unsigned_abs();
+        let dy = (self.y as isize - other.y as isize).unsigned_abs();
+        dx.max(dy)
+    }
 }
\ No newline at end of file
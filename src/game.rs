@@ -0,0 +1,435 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::board::{Board, OrderError, PutError};
+use crate::bot::{Action, Bot, BotView};
+use crate::config::{BotConfig, Config, PhaseConfig};
+use crate::data::{Phase, Position, Response, Stance, Standing, TileType, Viewport};
+use crate::replay::{Recorder, ReplayEvent};
+use crate::server::Server;
+use crate::snapshot::Snapshot;
+use crate::win::WinCondition;
+
+pub const OPEN_WORLD: u64 = 0;
+
+// Where a match is in its build/evolve cycle. Only changed with the board lock
+// held, so a placement never races a phase switch.
+#[derive(Copy, Clone, Debug)]
+enum Cycle {
+    Build { ends_at: Instant },
+    Evolve { generations_left: usize },
+    // Matches without phases evolve forever and take placements at any time.
+    Continuous,
+}
+
+// A player the server plays itself.
+struct BotPlayer {
+    id: Uuid,
+    name: String,
+    bot: Box<dyn Bot>,
+}
+
+// One board and the thread ticking it. The open world never ends and can be
+// joined at any time; lobby matches are played out between the players they
+// started with.
+pub struct Match {
+    pub id: u64,
+    pub board: RwLock<Board>,
+    players: Vec<(Uuid, String)>,
+    recorder: Option<Mutex<Recorder>>,
+    running: AtomicBool,
+
+    phases: Option<PhaseConfig>,
+    cycle: Mutex<Cycle>,
+
+    // Empty for the open world, which never ends.
+    conditions: Vec<WinCondition>,
+
+    bots: Mutex<Vec<BotPlayer>>,
+    bots_added: AtomicUsize,
+    bot_config: BotConfig,
+}
+
+impl Match {
+    // `bots` more seats are taken by bots, which join through `spawn_bots`.
+    pub fn new(id: u64, board: Board, mut players: Vec<(Uuid, String)>, bots: usize, config: &Config) -> Self {
+        let recorder = match Recorder::create(&config.server.replay_dir, &board) {
+            Ok(recorder) => {
+                info!("Recording match {} to {}.", id, recorder.path().display());
+                Some(Mutex::new(recorder))
+            }
+            Err(err) => {
+                warn!("Failed to create replay file, not recording: {}", err);
+                None
+            }
+        };
+
+        let phases = Some(config.phases.clone()).filter(|phases| phases.applies_to(id == OPEN_WORLD));
+        let cycle = match &phases {
+            Some(phases) => Cycle::Build {
+                ends_at: Instant::now() + Duration::from_millis(phases.build_ms),
+            },
+            None => Cycle::Continuous,
+        };
+
+        let mut game = Self {
+            id,
+            board: board.into(),
+            players: Vec::new(),
+            recorder,
+            running: AtomicBool::new(true),
+            phases,
+            cycle: Mutex::new(cycle),
+            conditions: if id == OPEN_WORLD {
+                Vec::new()
+            } else {
+                config.lobby.win.clone()
+            },
+            bots: Mutex::new(Vec::new()),
+            bots_added: AtomicUsize::new(0),
+            bot_config: config.bots.clone(),
+        };
+
+        let bots = (0..bots).filter_map(|_| game.new_bot()).collect::<Vec<_>>();
+        players.extend(bots.iter().map(|bot| (bot.id, bot.name.clone())));
+        game.players = players;
+        game.bots = Mutex::new(bots);
+        game
+    }
+
+    // `None` if no strategies are configured.
+    fn new_bot(&self) -> Option<BotPlayer> {
+        let strategies = &self.bot_config.strategies;
+        if strategies.is_empty() {
+            return None;
+        }
+
+        let n = self.bots_added.fetch_add(1, Ordering::SeqCst);
+        let strategy = strategies[n % strategies.len()];
+        Some(BotPlayer {
+            id: Uuid::new_v4(),
+            name: format!("{} bot {}", strategy.name(), n + 1),
+            bot: strategy.bot(),
+        })
+    }
+
+    fn cycle(&self) -> Cycle {
+        *self.cycle.lock().unwrap()
+    }
+
+    fn set_cycle(&self, cycle: Cycle) {
+        *self.cycle.lock().unwrap() = cycle;
+    }
+
+    pub fn accepts_placements(&self) -> bool {
+        !matches!(self.cycle(), Cycle::Evolve { .. })
+    }
+
+    // The current phase and its countdown, for matches that have phases.
+    pub fn phase(&self) -> Option<Response> {
+        match self.cycle() {
+            Cycle::Build { ends_at } => Some(Response::PHASE_CHANGE {
+                phase: Phase::BUILD,
+                remaining_ms: Some(ends_at.saturating_duration_since(Instant::now()).as_millis() as u64),
+                remaining_generations: None,
+            }),
+            Cycle::Evolve { generations_left } => Some(Response::PHASE_CHANGE {
+                phase: Phase::EVOLVE,
+                remaining_ms: None,
+                remaining_generations: Some(generations_left),
+            }),
+            Cycle::Continuous => None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.id == OPEN_WORLD
+    }
+
+    pub fn players(&self) -> &[(Uuid, String)] {
+        &self.players
+    }
+
+    // Call while still holding the board lock the event was applied under, so
+    // that the recorded order matches the order the board saw.
+    pub fn record(&self, gen: usize, event: ReplayEvent) {
+        if let Some(Ok(mut recorder)) = self.recorder.as_ref().map(Mutex::lock) {
+            recorder.record(gen, event);
+        }
+    }
+
+    // Drops a new player's colony onto the board. Returns false if there was
+    // no room for it.
+    pub fn spawn(&self, board: &mut Board, id: Uuid, name: String) -> bool {
+        let spawn = board.spawn_player(id);
+        self.record(board.generation(), ReplayEvent::JOIN { id });
+
+        match spawn.and_then(|_| board.get_player_mut(id)) {
+            Some(player) => {
+                player.name = Some(name);
+                player.token = Uuid::new_v4();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Spawns the bots the match was created with. Bots that found no room are
+    // dropped.
+    pub fn spawn_bots(&self, board: &mut Board) {
+        if let Ok(mut bots) = self.bots.lock() {
+            bots.retain(|bot| self.spawn(board, bot.id, bot.name.clone()));
+        }
+    }
+
+    // Everything that places cells goes through here, clients and bots alike.
+    // Returns the player's remaining energy.
+    pub fn put(&self, board: &mut Board, id: Uuid, position: Position, tile: TileType) -> Result<u32, PutError> {
+        if !self.accepts_placements() {
+            return Err(PutError::WrongPhase);
+        }
+        let erg = board.put(id, position, tile)?;
+        self.record(board.generation(), ReplayEvent::PUT { id, position, tile });
+        Ok(erg)
+    }
+
+    // Orders can be given in any phase, fighters carry them out as the board
+    // evolves. Both return how many units took the order.
+    pub fn set_rally(&self, board: &mut Board, id: Uuid, area: Viewport, position: Position) -> Result<usize, OrderError> {
+        let units = board.set_rally(id, area, position)?;
+        self.record(board.generation(), ReplayEvent::SET_RALLY { id, area, position });
+        Ok(units)
+    }
+
+    pub fn set_stance(&self, board: &mut Board, id: Uuid, area: Viewport, stance: Stance) -> Result<usize, OrderError> {
+        let units = board.set_stance(id, area, stance)?;
+        self.record(board.generation(), ReplayEvent::SET_STANCE { id, area, stance });
+        Ok(units)
+    }
+
+    // Lets every bot place a cell, and keeps the open world topped up with
+    // bots. Returns true if a bot joined or left.
+    fn run_bots(&self, board: &mut Board) -> bool {
+        let mut bots = match self.bots.lock() {
+            Ok(bots) => bots,
+            Err(_) => return false,
+        };
+        bots.retain(|bot| board.get_player(bot.id).is_some());
+
+        let mut changed = false;
+        if self.is_open() && self.bot_config.open_world > 0 {
+            let players = board.players().count();
+            if players < self.bot_config.open_world {
+                if let Some(bot) = self.new_bot() {
+                    if self.spawn(board, bot.id, bot.name.clone()) {
+                        info!("Bot {} joined the open world.", bot.name);
+                        bots.push(bot);
+                        changed = true;
+                    }
+                }
+            } else if players > self.bot_config.open_world {
+                if let Some(bot) = bots.pop() {
+                    info!("Bot {} left the open world to make room.", bot.name);
+                    board.remove_player(bot.id);
+                    self.record(board.generation(), ReplayEvent::LEAVE { id: bot.id });
+                    changed = true;
+                }
+            }
+        }
+
+        if !self.accepts_placements() {
+            return changed;
+        }
+        for player in bots.iter_mut() {
+            let action = BotView::new(board, player.id, self.bot_config.view_radius)
+                .and_then(|view| player.bot.think(&view));
+            if let Some(Action::Put { position, tile }) = action {
+                if let Err(err) = self.put(board, player.id, position, tile) {
+                    debug!("Bot {} could not place {:?} at {:?}: {}", player.name, tile, position, err);
+                }
+            }
+        }
+        changed
+    }
+
+    fn broadcast_palette(&self, server: &Server, board: &Board) {
+        server.broadcast_to(self.id, &Response::PALETTE {
+            teams: board.palette(),
+        });
+    }
+
+    pub fn start(self: &Arc<Self>, server: Arc<Server>) {
+        let game = Arc::clone(self);
+        std::thread::spawn(move || game.run(&server));
+    }
+
+    fn run(&self, server: &Arc<Server>) {
+        let settings = &server.config.server;
+
+        while server.running.load(Ordering::SeqCst) && self.running.load(Ordering::SeqCst) {
+            let tick = Duration::from_millis(settings.tick_ms);
+
+            // The board is paused while players build, but what they place
+            // still goes out every tick, along with the countdown.
+            if let Cycle::Build { ends_at } = self.cycle() {
+                let mut gen = 0;
+                let mut changes = Vec::new();
+                let mut energy = HashMap::new();
+                if let Ok(mut board) = self.board.write() {
+                    if self.run_bots(&mut board) {
+                        self.broadcast_palette(server, &board);
+                    }
+                    gen = board.generation();
+                    changes = board.take_changes();
+                    energy = board.players().map(|player| (player.id, player.energy)).collect();
+                }
+                if !changes.is_empty() {
+                    server.send_deltas(self.id, gen, &changes);
+                }
+                server.send_energy(self.id, &energy);
+
+                let now = Instant::now();
+                if now < ends_at {
+                    self.broadcast_phase(server);
+                    std::thread::sleep(tick.min(ends_at - now));
+                    continue;
+                }
+
+                let evolve = Cycle::Evolve {
+                    generations_left: self.phases.as_ref().map_or(0, |phases| phases.evolve_generations),
+                };
+                if let Ok(_board) = self.board.write() {
+                    self.set_cycle(evolve);
+                }
+                self.broadcast_phase(server);
+            }
+
+            std::thread::sleep(tick);
+
+            let start = Instant::now();
+
+            let mut gen = 0;
+            let mut changes = Vec::new();
+            let mut energy = HashMap::new();
+            let mut snapshot = None;
+            let mut outcome = None;
+            if let Ok(mut board) = self.board.write() {
+                board.next();
+                gen = board.generation();
+
+                for id in board.expired_players(settings.reconnect_grace) {
+                    info!("Player (id: {}) did not come back in time, removing.", id);
+                    board.remove_player(id);
+                    self.record(gen, ReplayEvent::LEAVE { id });
+                }
+
+                if self.run_bots(&mut board) {
+                    self.broadcast_palette(server, &board);
+                }
+
+                changes = board.take_changes();
+                energy = board.players().map(|player| (player.id, player.energy)).collect();
+
+                if self.is_open() && gen.is_multiple_of(settings.snapshot_interval) {
+                    snapshot = Some(Snapshot::capture(&board));
+                }
+
+                if gen.is_multiple_of(settings.leaderboard_interval) {
+                    server.broadcast_to(self.id, &Response::LEADERBOARD_UPDATE {
+                        leaderboard: board.get_leaderboard()
+                    });
+                }
+
+                outcome = self.outcome(&board);
+
+                if let (Cycle::Evolve { generations_left }, Some(phases)) = (self.cycle(), &self.phases) {
+                    self.set_cycle(if generations_left > 1 {
+                        Cycle::Evolve {
+                            generations_left: generations_left - 1,
+                        }
+                    } else {
+                        Cycle::Build {
+                            ends_at: Instant::now() + Duration::from_millis(phases.build_ms),
+                        }
+                    });
+                }
+            }
+
+            let elapsed = start.elapsed();
+
+            if let Some(snapshot) = snapshot {
+                if let Err(err) = snapshot.save(&settings.snapshot_dir) {
+                    warn!("Failed to save snapshot: {}", err);
+                }
+            }
+
+            server.send_deltas(self.id, gen, &changes);
+            server.send_energy(self.id, &energy);
+            server.broadcast_to(self.id, &Response::GENERATION_PING { gen });
+            if let Cycle::Build { .. } = self.cycle() {
+                self.broadcast_phase(server);
+            }
+
+            debug!("Match {} generation {} generated in {} ms ({} ns)", self.id, gen, elapsed.as_millis(), elapsed.as_nanos());
+
+            if let Some(outcome) = outcome {
+                self.running.store(false, Ordering::SeqCst);
+                server.end_match(self.id, outcome);
+            }
+        }
+        info!("Match {} done.", self.id);
+    }
+
+    fn broadcast_phase(&self, server: &Server) {
+        if let Some(phase) = self.phase() {
+            server.broadcast_to(self.id, &phase);
+        }
+    }
+
+    // Lobby matches are over once one of their win conditions is met.
+    // Standings go by survival, then size, with the winner on top.
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        if self.is_open() {
+            return None;
+        }
+
+        let ids = self.players.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+        let (condition, winner) = self
+            .conditions
+            .iter()
+            .find_map(|condition| condition.check(board, &ids).map(|winner| (condition.clone(), winner)))?;
+
+        let mut standings = self
+            .players
+            .iter()
+            .map(|(id, name)| Standing {
+                name: Some(name.clone()),
+                ..board.standing(*id)
+            })
+            .collect::<Vec<_>>();
+        standings.sort_by_key(|standing| {
+            (
+                Reverse(Some(standing.id) == winner),
+                Reverse(standing.alive),
+                Reverse(standing.cells),
+                standing.id,
+            )
+        });
+
+        Some(Outcome {
+            condition,
+            winner,
+            standings,
+        })
+    }
+}
+
+pub struct Outcome {
+    pub condition: WinCondition,
+    pub winner: Option<Uuid>,
+    pub standings: Vec<Standing>,
+}
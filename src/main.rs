@@ -1,4 +1,5 @@
 #![allow(unused)]
+#![allow(clippy::upper_case_acronyms)]
 
 extern crate serde;
 extern crate serde_json;
@@ -7,145 +8,304 @@ extern crate serde_derive;
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate clap;
 extern crate env_logger;
 
 mod board;
+mod bot;
+mod config;
 mod data;
+mod game;
+mod path;
+mod replay;
+mod scenario;
 mod server;
+mod snapshot;
+mod tiles;
 mod utils;
+mod win;
 mod constants;
 
 use board::*;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use config::Config;
+use tiles::TileSet;
 use data::{Position, TileType, Unit};
 use server::Server;
 use uuid::Uuid;
 
+#[allow(clippy::result_large_err)]
 fn main() -> ws::Result<()> {
-    // let mut board = board::Board::new();
-
-    // let team_1 = Uuid::new_v4();
-
-    // board.set(Position { x: 5, y: 5 }, Unit::new_queen(team_1));
-
-    // board.set(
-    //     Position { x: 8, y: 8 },
-    //     Unit {
-    //         hp: 1,
-    //         tile: TileType::FEEDER,
-    //         team: team_1,
-    //         ..Default::default()
-    //     },
-    // );
-    // board.set(
-    //     Position { x: 4, y: 6 },
-    //     Unit {
-    //         hp: 600,
-    //         tile: TileType::GUARD,
-    //         team: team_1,
-    //         ..Default::default()
-    //     },
-    // );
-    // board.set(
-    //     Position { x: 8, y: 7 },
-    //     Unit {
-    //         hp: 1,
-    //         tile: TileType::BOLSTER,
-    //         team: team_1,
-    //         ..Default::default()
-    //     },
-    // );
-
-    // let team_2 = Uuid::new_v4();
-
-    // board.set(
-    //     Position { x: 0, y: 9 },
-    //     Unit {
-    //         hp: 10,
-    //         tile: TileType::ATTACK,
-    //         team: team_2,
-    //         ..Default::default()
-    //     },
-    // );
-
-    // board.set(
-    //     Position { x: 1, y: 9 },
-    //     Unit {
-    //         hp: 10,
-    //         tile: TileType::ATTACK,
-    //         team: team_2,
-    //         ..Default::default()
-    //     },
-    // );
-
-    // for _ in 0..50 {
-    //     board.next();
-    //     std::thread::sleep(std::time::Duration::from_millis(200));
-    //     println!("{}", board);
-    //     // board.get(board::Position { x: 5, y: 5 }).map(|u| println!("{}", u));
-    // }
-
-    // let window = board.get_window(5, 5, 5, 5);
-
-    // let mut s = String::new();
-    // for row in window {
-    //     for col in row {
-    //         print!("{}", col);
-    //     }
-    //     println!();
-    // }
-    // println!("{}", s);
-
-    // return Ok(());
-
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
         .filter_module("ws::handler", log::LevelFilter::Info)
         .init();
 
-    let mut server = Server::new();
+    let matches = app().get_matches();
+    if let Some(matches) = matches.subcommand_matches("replay") {
+        run_replay(matches);
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("simulate") {
+        run_simulation(matches);
+        return Ok(());
+    }
+
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Failed to load config: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let bind = config.server.bind.clone();
+
+    let mut server = Server::new(config);
 
     let mut arcserver = Arc::new(server);
-    make_game_thread(arcserver.clone());
+    arcserver.start();
+
+    ws::listen(bind, |out| Server::new_client(arcserver.clone(), out))
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("tile_game")
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .help("TOML file to read settings from"),
+        )
+        .arg(
+            Arg::with_name("tiles")
+                .long("tiles")
+                .value_name("FILE")
+                .help("TOML file with tile definitions"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDR")
+                .help("Address to listen on"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed for a new board"),
+        )
+        .arg(
+            Arg::with_name("tick-ms")
+                .long("tick-ms")
+                .value_name("MS")
+                .help("Milliseconds between generations"),
+        )
+        .arg(
+            Arg::with_name("x-size")
+                .long("x-size")
+                .value_name("CELLS")
+                .help("Board width"),
+        )
+        .arg(
+            Arg::with_name("y-size")
+                .long("y-size")
+                .value_name("CELLS")
+                .help("Board height"),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Re-runs a recorded match offline")
+                .arg(Arg::with_name("file").required(true))
+                .arg(Arg::with_name("generation"))
+                .arg(
+                    Arg::with_name("save")
+                        .long("save")
+                        .value_name("FILE")
+                        .help("Write the final board out as a scenario"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .about("Runs a scenario file without a server and reports on every team")
+                .arg(Arg::with_name("scenario").required(true))
+                .arg(
+                    Arg::with_name("generations")
+                        .long("generations")
+                        .short("g")
+                        .value_name("N")
+                        .help("Generations to run, instead of the scenario's own"),
+                )
+                .arg(
+                    Arg::with_name("every")
+                        .long("every")
+                        .value_name("N")
+                        .help("Also report every N generations along the way"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print one JSON report per line"),
+                )
+                .arg(
+                    Arg::with_name("no-board")
+                        .long("no-board")
+                        .help("Leave the board out of reports"),
+                )
+                .arg(
+                    Arg::with_name("save")
+                        .long("save")
+                        .value_name("FILE")
+                        .help("Write the final board out as a scenario"),
+                ),
+        )
+}
 
-    ws::listen("127.0.0.1:2794", |out| Server::new_client(arcserver.clone(), out))
+// Defaults, then the config file, then command line flags.
+fn load_config(matches: &ArgMatches) -> Result<Config, String> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(path).map_err(|err| format!("{}: {}", path, err))?,
+        None => Config::default(),
+    };
+
+    if let Some(path) = matches.value_of("tiles") {
+        config.game.tiles = TileSet::load(path).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    if let Some(bind) = matches.value_of("bind") {
+        config.server.bind = bind.to_owned();
+    }
+    if matches.is_present("seed") {
+        config.server.seed = Some(value_t!(matches, "seed", u64).map_err(|err| err.message)?);
+    }
+    if matches.is_present("tick-ms") {
+        config.server.tick_ms = value_t!(matches, "tick-ms", u64).map_err(|err| err.message)?;
+    }
+    if matches.is_present("x-size") {
+        config.game.x_size = value_t!(matches, "x-size", usize).map_err(|err| err.message)?;
+    }
+    if matches.is_present("y-size") {
+        config.game.y_size = value_t!(matches, "y-size", usize).map_err(|err| err.message)?;
+    }
+    config.game.check().map_err(|err| err.to_string())?;
+
+    Ok(config)
 }
 
 use crate::data::{Request, Response};
+use crate::replay::ReplayEvent;
+use crate::snapshot::Snapshot;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::{Duration, Instant};
 
-fn make_game_thread(server: Arc<Server>) {
-    let board = server.board.clone();
-    let running = server.running.clone();
+// `tile_game replay <file> [generation]`: re-runs a recorded match offline and
+// prints where every colony ended up.
+fn run_replay(matches: &ArgMatches) {
+    let path = matches.value_of("file").unwrap();
+    let until = matches.value_of("generation").and_then(|gen| gen.parse().ok());
 
-    let handle = std::thread::spawn(move || {
-        let mut gen: usize = 0;
-        while running.load(Ordering::SeqCst) {
-            std::thread::sleep(Duration::from_secs(1));
-            gen += 1;
+    match replay::run(path, until) {
+        Ok(board) => {
+            println!("seed {} generation {}", board.seed(), board.generation());
+            let mut players = board.players().collect::<Vec<_>>();
+            players.sort_unstable_by_key(|player| player.id);
+            for player in players {
+                println!(
+                    "{} {} cells={} erg={}",
+                    player.id,
+                    player.name.as_deref().unwrap_or("-"),
+                    board.team_size(player.id),
+                    player.energy
+                );
+            }
+            save_scenario(matches, &board);
+        }
+        Err(err) => error!("Failed to replay {}: {}", path, err),
+    }
+}
+
+#[derive(Serialize)]
+struct SimulationReport {
+    seed: u64,
+    generation: usize,
+    teams: Vec<data::Standing>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    board: Option<Vec<String>>,
+}
 
-            let start = Instant::now();
+// `tile_game simulate <scenario>`: runs a scenario for a number of
+// generations and reports how each team is doing, for balance experiments.
+fn run_simulation(matches: &ArgMatches) {
+    let path = matches.value_of("scenario").unwrap();
+    let scenario = match scenario::Scenario::load(path) {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            error!("Failed to load scenario {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    let mut board = match scenario.build() {
+        Ok(board) => board,
+        Err(err) => {
+            error!("Failed to set up scenario {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
 
-            board.write().map(|mut board| {
-                board.next();
-                
-                if gen % 20 == 0 {
-                    server.broadcast(&Response::LEADERBOARD_UPDATE {
-                        leaderboard: board.get_leaderboard()
-                    });
-                    info!("Broadcasting leaderboards...");
-                }
-            });
+    let generations = value_t!(matches, "generations", usize).unwrap_or(scenario.generations);
+    let every = value_t!(matches, "every", usize).ok().filter(|&every| every > 0);
+    let json = matches.is_present("json");
+    let show_board = !matches.is_present("no-board");
 
-            let elapsed = start.elapsed();
+    let ids = board.palette().iter().map(|team| team.id).collect::<Vec<_>>();
+    let report = |board: &Board| {
+        let report = SimulationReport {
+            seed: board.seed(),
+            generation: board.generation(),
+            teams: ids.iter().map(|&id| board.standing(id)).collect(),
+            board: Some(board.to_string().lines().map(str::to_owned).collect()).filter(|_| show_board),
+        };
 
-            server.broadcast(&Response::GENERATION_PING { gen });
+        if json {
+            println!("{}", serde_json::to_string(&report).unwrap());
+            return;
+        }
+        println!("seed {} generation {}", report.seed, report.generation);
+        for team in &report.teams {
+            println!(
+                "{} cells={} alive={} peak={} kills={} spent={} earned={} survived={}",
+                team.name.as_deref().unwrap_or("-"),
+                team.cells,
+                team.alive,
+                team.peak_cells,
+                team.kills,
+                team.energy_spent,
+                team.energy_earned,
+                team.generations_survived
+            );
+        }
+        for row in report.board.iter().flatten() {
+            println!("|{}|", row);
+        }
+    };
+
+    for _ in 0..generations {
+        board.next_with(&scenario.rules);
+        if every.is_some_and(|every| board.generation().is_multiple_of(every) && board.generation() < generations) {
+            report(&board);
+        }
+    }
+    report(&board);
+    save_scenario(matches, &board);
+}
 
-            info!("Generation {} generated in {} ms ({} ns)", gen, elapsed.as_millis(), elapsed.as_nanos());
+fn save_scenario(matches: &ArgMatches, board: &Board) {
+    if let Some(path) = matches.value_of("save") {
+        if let Err(err) = scenario::export(board).and_then(|text| std::fs::write(path, text)) {
+            error!("Failed to save scenario to {}: {}", path, err);
         }
-        info!("Done.");
-    });
-    // handle
+    }
 }
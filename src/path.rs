@@ -0,0 +1,117 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::config::GameConfig;
+use crate::constants;
+use crate::data::Position;
+
+// Where a path may end: on the target itself, or on any cell next to it for
+// units that mean to attack whatever stands there.
+#[derive(Copy, Clone, Debug)]
+pub enum Goal {
+    At(Position),
+    NextTo(Position),
+}
+
+impl Goal {
+    fn target(self) -> Position {
+        match self {
+            Goal::At(target) | Goal::NextTo(target) => target,
+        }
+    }
+
+    fn reached(self, pos: Position) -> bool {
+        match self {
+            Goal::At(target) => pos == target,
+            Goal::NextTo(target) => pos != target && pos.distance(target) <= 1,
+        }
+    }
+
+    // Straight line distance to the target, squared. Breaks ties between
+    // equally short paths in favour of the most direct one.
+    fn directness(self, pos: Position) -> usize {
+        let target = self.target();
+        let dx = (pos.x as isize - target.x as isize).unsigned_abs();
+        let dy = (pos.y as isize - target.y as isize).unsigned_abs();
+        dx * dx + dy * dy
+    }
+
+    // Fewest steps left, which diagonal moves make the larger of the two axes.
+    fn estimate(self, pos: Position) -> usize {
+        match self {
+            Goal::At(target) => pos.distance(target),
+            Goal::NextTo(target) => pos.distance(target).saturating_sub(1),
+        }
+    }
+}
+
+// A* over the 8-connected grid, only stepping into passable cells and never
+// squeezing diagonally between two that are not. Gives up after expanding
+// `limit` cells. Returns the first step of a shortest path, if one was found
+// and the unit is not already there, and how many cells were expanded.
+// `None` does not tell giving up from there being no way through.
+// Ties go to the path closest to a straight line, then by position, so the
+// same board always gives the same path.
+pub fn first_step<F>(config: &GameConfig, from: Position, goal: Goal, passable: F, limit: usize) -> (Option<Position>, usize)
+where
+    F: Fn(Position) -> bool,
+{
+    if goal.reached(from) {
+        return (None, 0);
+    }
+
+    // Cells reached so far, with the step count and the cell they came from.
+    let mut came_from = HashMap::new();
+    came_from.insert(from, (0, from));
+    let mut open = BinaryHeap::new();
+    open.push(Reverse((goal.estimate(from), goal.directness(from), 0, from)));
+
+    let mut expanded = 0;
+    while let Some(Reverse((_, _, steps, pos))) = open.pop() {
+        if came_from[&pos].0 < steps {
+            continue;
+        }
+        if goal.reached(pos) {
+            let mut step = pos;
+            while came_from[&step].1 != from {
+                step = came_from[&step].1;
+            }
+            return (Some(step), expanded);
+        }
+        if expanded >= limit {
+            break;
+        }
+        expanded += 1;
+
+        for &(dx, dy) in constants::ALL_OFFSETS.iter() {
+            let next = match config.adj_position(pos, (dx, dy)) {
+                Some(next) if passable(next) => next,
+                _ => continue,
+            };
+            if dx != 0 && dy != 0 {
+                let open = |offset| config.adj_position(pos, offset).is_some_and(&passable);
+                if !open((dx, 0)) && !open((0, dy)) {
+                    continue;
+                }
+            }
+            if came_from.get(&next).is_none_or(|&(best, _)| steps + 1 < best) {
+                came_from.insert(next, (steps + 1, pos));
+                open.push(Reverse((steps + 1 + goal.estimate(next), goal.directness(next), steps + 1, next)));
+            }
+        }
+    }
+
+    (None, expanded)
+}
+
+// One step straight towards the goal, for when there is no budget left to
+// look for a way around. `None` if the unit is already there.
+pub fn greedy_step(config: &GameConfig, from: Position, goal: Goal) -> Option<Position> {
+    if goal.reached(from) {
+        return None;
+    }
+    let target = goal.target();
+    let dx = (target.x as isize - from.x as isize).signum();
+    let dy = (target.y as isize - from.y as isize).signum();
+    config.adj_position(from, (dx, dy))
+}
@@ -0,0 +1,268 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::board::Board;
+use crate::config::GameConfig;
+use crate::data::{Position, Stance, TileType, Viewport};
+use crate::snapshot::Snapshot;
+
+pub const REPLAY_VERSION: u32 = 6;
+
+// First line of a replay file. Everything needed to rebuild the board before
+// the first input is applied, including the rules it was played under.
+// Matches resumed from a snapshot carry it along, since their starting board
+// can not be derived from the seed alone.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub seed: u64,
+    pub config: GameConfig,
+    #[serde(default)]
+    pub start: Option<Snapshot>,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ReplayEvent {
+    JOIN {
+        id: Uuid,
+    },
+    LEAVE {
+        id: Uuid,
+    },
+    PUT {
+        id: Uuid,
+        position: Position,
+        tile: TileType,
+    },
+    SET_RALLY {
+        id: Uuid,
+        area: Viewport,
+        position: Position,
+    },
+    SET_STANCE {
+        id: Uuid,
+        area: Viewport,
+        stance: Stance,
+    },
+}
+
+impl ReplayEvent {
+    pub fn apply(&self, board: &mut Board) {
+        match *self {
+            ReplayEvent::JOIN { id } => {
+                board.spawn_player(id);
+            }
+            ReplayEvent::LEAVE { id } => board.remove_player(id),
+            ReplayEvent::PUT { id, position, tile } => {
+                if let Err(err) = board.put(id, position, tile) {
+                    warn!("Replayed PUT by {} was rejected: {:?}.", id, err);
+                }
+            }
+            ReplayEvent::SET_RALLY { id, area, position } => {
+                if let Err(err) = board.set_rally(id, area, position) {
+                    warn!("Replayed SET_RALLY by {} was rejected: {:?}.", id, err);
+                }
+            }
+            ReplayEvent::SET_STANCE { id, area, stance } => {
+                if let Err(err) = board.set_stance(id, area, stance) {
+                    warn!("Replayed SET_STANCE by {} was rejected: {:?}.", id, err);
+                }
+            }
+        }
+    }
+}
+
+// One line per input. `gen` is the board generation the input was applied
+// after, so it lands between tick `gen` and tick `gen + 1`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    pub gen: usize,
+    pub event: ReplayEvent,
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(dir: impl AsRef<Path>, board: &Board) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (path, file) = Self::open_new(dir.as_ref(), board)?;
+        let mut recorder = Self {
+            out: BufWriter::new(file),
+            path,
+        };
+
+        let fresh = board.generation() == 0 && board.units().next().is_none();
+        recorder.write_line(&ReplayHeader {
+            version: REPLAY_VERSION,
+            seed: board.seed(),
+            config: board.config().clone(),
+            start: if fresh { None } else { Some(Snapshot::capture(board)) },
+        })?;
+        Ok(recorder)
+    }
+
+    // Seeds are fixed in config and lobby matches number from 1 again after a
+    // restart, so the plain name may already be taken by an earlier match.
+    // Never truncate one; count up until a name is free.
+    fn open_new(dir: &Path, board: &Board) -> io::Result<(PathBuf, File)> {
+        let stem = format!("{}-{}", board.seed(), board.generation());
+        for n in 0.. {
+            let path = match n {
+                0 => dir.join(format!("{}.jsonl", stem)),
+                n => dir.join(format!("{}-{}.jsonl", stem, n)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, gen: usize, event: ReplayEvent) {
+        if let Err(err) = self.write_line(&ReplayEntry { gen, event }) {
+            warn!("Failed to record replay entry: {}", err);
+        }
+    }
+
+    fn write_line<T: serde::Serialize>(&mut self, value: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, value)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Rebuilds a board from a replay file. The board is advanced up to the
+// generation of the last recorded input, or to `until` if that is later.
+pub fn run(path: impl AsRef<Path>, until: Option<usize>) -> io::Result<Board> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    let header: ReplayHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err(invalid_data("Replay file is empty".to_owned())),
+    };
+    if header.version != REPLAY_VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported replay version {} (expected {})",
+            header.version, REPLAY_VERSION
+        )));
+    }
+
+    let config = Arc::new(header.config);
+    let mut board = match header.start {
+        Some(snapshot) => snapshot.restore(config)?,
+        None => Board::with_seed(config, header.seed),
+    };
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: ReplayEntry = serde_json::from_str(&line)?;
+        while board.generation() < entry.gen {
+            board.next();
+        }
+        entry.event.apply(&mut board);
+    }
+
+    while board.generation() < until.unwrap_or(0) {
+        board.next();
+    }
+
+    Ok(board)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::scenario;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tile_game-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Inputs are recorded the way `Game` records them, right after the board
+    // took them, and the replayed board has to end up exactly where the live
+    // one did.
+    #[test]
+    fn replays_rebuild_the_board() {
+        let dir = scratch_dir("replay");
+        let config = Arc::new(GameConfig {
+            x_size: 40,
+            y_size: 40,
+            ..GameConfig::default()
+        });
+        let mut board = Board::with_seed(config, 11);
+        let mut recorder = Recorder::create(&dir, &board).unwrap();
+
+        let ids = [1, 2, 3].map(Uuid::from_u128);
+        let mut queens = Vec::new();
+        for &id in &ids {
+            queens.push(board.spawn_player(id).unwrap());
+            recorder.record(board.generation(), ReplayEvent::JOIN { id });
+            board.next();
+        }
+        let mut puts = 0;
+        for round in 0..3 {
+            for (&id, queen) in ids.iter().zip(&queens) {
+                let position = Position { x: queen.x + 1, y: queen.y + round };
+                let tile = if round == 0 { TileType::SPAWNER } else { TileType::ATTACK };
+                if board.put(id, position, tile).is_ok() {
+                    puts += 1;
+                    recorder.record(board.generation(), ReplayEvent::PUT { id, position, tile });
+                }
+            }
+            board.next();
+            board.next();
+        }
+        assert!(puts > 0, "no placement was accepted");
+        board.remove_player(ids[1]);
+        recorder.record(board.generation(), ReplayEvent::LEAVE { id: ids[1] });
+        for _ in 0..5 {
+            board.next();
+        }
+        let path = recorder.path().to_owned();
+        drop(recorder);
+
+        let replayed = run(&path, Some(board.generation())).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replayed.generation(), board.generation());
+        assert_eq!(scenario::export(&replayed).unwrap(), scenario::export(&board).unwrap());
+    }
+
+    #[test]
+    fn recordings_never_overwrite() {
+        let dir = scratch_dir("recorders");
+        let board = Board::with_seed(Arc::new(GameConfig::default()), 5);
+        let first = Recorder::create(&dir, &board).unwrap();
+        let second = Recorder::create(&dir, &board).unwrap();
+        let third = Recorder::create(&dir, &board).unwrap();
+
+        let names = [&first, &second, &third].map(|recorder| recorder.path().file_name().unwrap().to_owned());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names, ["5-0.jsonl", "5-0-1.jsonl", "5-0-2.jsonl"]);
+    }
+}
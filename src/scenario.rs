@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::board::{Board, PlayerInformation, Rule};
+use crate::config::GameConfig;
+use crate::data::{Position, Stance, TileType, Unit};
+use crate::tiles::TileSet;
+
+// Team symbols used when a board is exported, by palette index.
+const SYMBOLS: &str = "123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+// A hand-made starting board, for running the rules without a server.
+//
+// Units can be listed per team, or drawn in `grid` with two characters per
+// cell: the tile's letter as `Board` prints it, then a symbol that `legend`
+// maps to a team name. Empty cells are `..`. A grid also sets the board size.
+//
+//     grid = """
+//     ..Q1..
+//     ..b1A2
+//     """
+//     legend = { "1" = "defenders", "2" = "raiders" }
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    pub seed: u64,
+    // How many generations to run if the command line does not say.
+    pub generations: usize,
+    // The rules each generation runs, all of them unless narrowed down.
+    pub rules: Vec<Rule>,
+    // Tile definitions to use instead of the built-in ones.
+    pub tiles: Option<String>,
+    pub grid: Option<String>,
+    pub legend: BTreeMap<String, String>,
+    pub game: GameConfig,
+    pub teams: Vec<TeamSetup>,
+    // hp, armor and orders for units drawn in the grid.
+    pub overrides: Vec<UnitOverride>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            generations: 0,
+            rules: Rule::ALL.to_vec(),
+            tiles: None,
+            grid: None,
+            legend: BTreeMap::new(),
+            game: GameConfig::default(),
+            teams: Vec::new(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct TeamSetup {
+    pub name: String,
+    // Defaults to the game's starting energy.
+    #[serde(default)]
+    pub energy: Option<u32>,
+    #[serde(default)]
+    pub units: Vec<UnitSetup>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct UnitSetup {
+    pub tile: TileType,
+    pub x: usize,
+    pub y: usize,
+    // Override the tile's base hp and the starting armor of zero.
+    #[serde(default)]
+    pub hp: Option<u32>,
+    #[serde(default)]
+    pub am: Option<u32>,
+    // Orders for fighters, as `SET_RALLY` and `SET_STANCE` would give them.
+    #[serde(default)]
+    pub rally: Option<Position>,
+    #[serde(default)]
+    pub stance: Option<Stance>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct UnitOverride {
+    pub x: usize,
+    pub y: usize,
+    #[serde(default)]
+    pub hp: Option<u32>,
+    #[serde(default)]
+    pub am: Option<u32>,
+    #[serde(default)]
+    pub rally: Option<Position>,
+    #[serde(default)]
+    pub stance: Option<Stance>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Teams are numbered from 1 in the order they are listed, so runs of the same
+// scenario always use the same ids.
+pub fn team_id(index: usize) -> Uuid {
+    Uuid::from_u128(index as u128 + 1)
+}
+
+// The units drawn in a grid, and the grid's size.
+struct Grid {
+    x_size: usize,
+    y_size: usize,
+    units: Vec<(Position, TileType, char)>,
+}
+
+impl Grid {
+    fn parse(text: &str) -> io::Result<Self> {
+        let rows = text
+            .lines()
+            .map(str::trim_end)
+            .skip_while(|row| row.is_empty())
+            .collect::<Vec<_>>();
+        let rows = match rows.iter().rposition(|row| !row.is_empty()) {
+            Some(last) => &rows[..=last],
+            None => return Err(invalid_data("The grid is empty".to_owned())),
+        };
+
+        let mut grid = Self {
+            x_size: 0,
+            y_size: rows.len(),
+            units: Vec::new(),
+        };
+        for (y, row) in rows.iter().enumerate() {
+            let cells = row.chars().collect::<Vec<_>>();
+            grid.x_size = grid.x_size.max(cells.len().div_ceil(2));
+
+            for (x, cell) in cells.chunks(2).enumerate() {
+                let position = Position::new(x, y);
+                let tile = TileType::from_symbol(cell[0])
+                    .ok_or_else(|| invalid_data(format!("Unknown tile {:?} at {:?}", cell[0], position)))?;
+                if tile == TileType::EMPTY {
+                    continue;
+                }
+                match cell.get(1) {
+                    Some(&symbol) if symbol != ' ' => grid.units.push((position, tile, symbol)),
+                    _ => return Err(invalid_data(format!("No team for the unit at {:?}", position))),
+                }
+            }
+        }
+        Ok(grid)
+    }
+}
+
+impl Scenario {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut scenario: Self = toml::from_str(text).map_err(|err| invalid_data(err.to_string()))?;
+        if let Some(tiles) = &scenario.tiles {
+            scenario.game.tiles = TileSet::load(tiles)?;
+        }
+        Ok(scenario)
+    }
+
+    pub fn build(&self) -> io::Result<Board> {
+        if let Some(symbol) = self.legend.keys().find(|symbol| symbol.chars().count() != 1) {
+            return Err(invalid_data(format!("Legend symbol {:?} is not a single character", symbol)));
+        }
+        let grid = self.grid.as_deref().map(Grid::parse).transpose()?;
+
+        let mut config = self.game.clone();
+        if let Some(grid) = &grid {
+            config.x_size = grid.x_size;
+            config.y_size = grid.y_size;
+        }
+        let config = Arc::new(config);
+        let mut board = Board::with_seed(Arc::clone(&config), self.seed);
+
+        // Listed teams first, then any only named in the legend.
+        let mut names = self.teams.iter().map(|team| team.name.as_str()).collect::<Vec<_>>();
+        for name in self.legend.values() {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+
+        for (index, name) in names.iter().enumerate() {
+            let setup = self.teams.iter().find(|team| team.name == *name);
+            board.add_player(PlayerInformation {
+                id: team_id(index),
+                name: Some((*name).to_owned()),
+                energy: setup.and_then(|team| team.energy).unwrap_or(config.init_erg),
+                token: Uuid::nil(),
+                disconnected_at: None,
+            })
+            .ok_or_else(|| invalid_data(format!("Too many teams, {:?} has no room", name)))?;
+        }
+
+        let place = |board: &mut Board, position: Position, tile: TileType, id: Uuid| {
+            if !board.in_bounds(position) {
+                return Err(invalid_data(format!("Unit out of bounds at {:?}", position)));
+            }
+            if board.get(position).is_some() {
+                return Err(invalid_data(format!("Two units at {:?}", position)));
+            }
+            if tile == TileType::EMPTY || config.tile(tile).is_none() {
+                return Err(invalid_data(format!("{:?} is not a defined tile", tile)));
+            }
+            board.set(position, Unit::new_unit(&config, id, position, tile));
+            Ok(())
+        };
+
+        for (index, team) in self.teams.iter().enumerate() {
+            for setup in &team.units {
+                let position = Position::new(setup.x, setup.y);
+                place(&mut board, position, setup.tile, team_id(index))?;
+                override_unit(&mut board, position, setup.hp, setup.am, setup.rally, setup.stance)?;
+            }
+        }
+
+        for (position, tile, symbol) in grid.map_or_else(Vec::new, |grid| grid.units) {
+            let name = self
+                .legend
+                .get(&symbol.to_string())
+                .ok_or_else(|| invalid_data(format!("Symbol {:?} at {:?} is not in the legend", symbol, position)))?;
+            let index = names.iter().position(|team| team == name).unwrap();
+            place(&mut board, position, tile, team_id(index))?;
+        }
+
+        for unit in &self.overrides {
+            let position = Position::new(unit.x, unit.y);
+            if !board.in_bounds(position) || board.get(position).is_empty() {
+                return Err(invalid_data(format!("No unit to override at {:?}", position)));
+            }
+            override_unit(&mut board, position, unit.hp, unit.am, unit.rally, unit.stance)?;
+        }
+
+        Ok(board)
+    }
+}
+
+fn override_unit(
+    board: &mut Board,
+    position: Position,
+    hp: Option<u32>,
+    am: Option<u32>,
+    rally: Option<Position>,
+    stance: Option<Stance>,
+) -> io::Result<()> {
+    if rally.is_some() || stance.is_some() {
+        if !board.config().is_mobile(board.get(position).tile) {
+            return Err(invalid_data(format!("The unit at {:?} can not take orders", position)));
+        }
+        if rally.is_some_and(|rally| !board.in_bounds(rally)) {
+            return Err(invalid_data(format!("Rally point out of bounds for the unit at {:?}", position)));
+        }
+    }
+    let unit = board.get_mut(position);
+    unit.hp = hp.unwrap_or(unit.hp);
+    unit.am = am.unwrap_or(unit.am);
+    unit.target_pos = rally.or(unit.target_pos);
+    unit.stance = stance.unwrap_or(unit.stance);
+    Ok(())
+}
+
+// The `[game]` section: `init_erg` always, the rest only where it differs
+// from the default.
+fn game_settings(config: &GameConfig) -> io::Result<String> {
+    let to_table = |config: &GameConfig| match toml::Value::try_from(config) {
+        Ok(toml::Value::Table(table)) => Ok(table),
+        Ok(_) => Err(invalid_data("Game settings are not a table".to_owned())),
+        Err(err) => Err(invalid_data(err.to_string())),
+    };
+    let defaults = to_table(&GameConfig::default())?;
+    let game = to_table(config)?
+        .into_iter()
+        .filter(|(key, value)| match key.as_str() {
+            "tiles" | "x_size" | "y_size" => false,
+            "init_erg" => true,
+            _ => defaults.get(key) != Some(value),
+        })
+        .collect();
+
+    let mut root = toml::value::Table::new();
+    root.insert("game".to_owned(), toml::Value::Table(game));
+    toml::to_string(&toml::Value::Table(root)).map_err(|err| invalid_data(err.to_string()))
+}
+
+fn quote(text: &str) -> String {
+    toml::Value::String(text.to_owned()).to_string()
+}
+
+// Writes a board out as a grid scenario that `Scenario::build` turns back into
+// the same units under the same rules. Every game setting that differs from
+// the default is written out, apart from the board size, which the grid sets.
+// Player stats and the RNG position are not kept, and custom tile definitions
+// have to be pointed to with `tiles` by hand.
+pub fn export(board: &Board) -> io::Result<String> {
+    let config = board.config();
+
+    let mut players = board.players().collect::<Vec<_>>();
+    players.sort_by_key(|player| board.team_index(player.id));
+
+    // Symbols go to the teams still on the board in palette order, so how
+    // many teams have come and gone before does not matter. Names are what
+    // the legend goes by, so they have to be unique.
+    if players.len() > SYMBOLS.len() {
+        return Err(invalid_data(format!("Can not export more than {} teams", SYMBOLS.len())));
+    }
+    let mut teams = Vec::<(Uuid, char, String)>::new();
+    for (player, symbol) in players.iter().zip(SYMBOLS.chars()) {
+        let base = player.name.clone().unwrap_or_else(|| format!("team {}", symbol));
+        let mut name = base.clone();
+        let mut n = 1;
+        while teams.iter().any(|(_, _, taken)| *taken == name) {
+            n += 1;
+            name = format!("{} ({})", base, n);
+        }
+        teams.push((player.id, symbol, name));
+    }
+
+    let mut out = String::new();
+    let mut overrides = String::new();
+    writeln!(out, "seed = {}", board.seed()).unwrap();
+    if config.tiles != TileSet::default() {
+        writeln!(out, "# Exported from a board with custom tiles; set `tiles` to use them.").unwrap();
+    }
+    writeln!(out, "grid = \"\"\"").unwrap();
+    for y in 0..config.y_size {
+        for x in 0..config.x_size {
+            let position = Position::new(x, y);
+            let unit = board.get(position);
+            match teams.iter().find(|(id, _, _)| *id == unit.team) {
+                Some(&(_, symbol, _)) if unit.is_some() => {
+                    write!(out, "{}{}", unit.tile, symbol).unwrap();
+                    // What the unit would be if built fresh where it stands.
+                    let fresh = Unit::new_unit(config, unit.team, position, unit.tile);
+                    let rally = unit.target_pos.filter(|_| unit.target_pos != fresh.target_pos);
+                    let stance = Some(unit.stance).filter(|&stance| stance != fresh.stance);
+                    if unit.hp != fresh.hp || unit.am != fresh.am || rally.is_some() || stance.is_some() {
+                        writeln!(overrides, "\n[[overrides]]\nx = {}\ny = {}\nhp = {}\nam = {}", x, y, unit.hp, unit.am).unwrap();
+                        if let Some(rally) = rally {
+                            writeln!(overrides, "rally = {{ x = {}, y = {} }}", rally.x, rally.y).unwrap();
+                        }
+                        if let Some(stance) = stance {
+                            writeln!(overrides, "stance = {}", quote(&format!("{:?}", stance))).unwrap();
+                        }
+                    }
+                }
+                _ => out.push_str(".."),
+            }
+        }
+        out.push('\n');
+    }
+    writeln!(out, "\"\"\"").unwrap();
+
+    writeln!(out, "\n[legend]").unwrap();
+    for (_, symbol, name) in &teams {
+        writeln!(out, "{} = {}", quote(&symbol.to_string()), quote(name)).unwrap();
+    }
+
+    out.push('\n');
+    out.push_str(&game_settings(config)?);
+
+    for ((_, _, name), player) in teams.iter().zip(&players) {
+        writeln!(out, "\n[[teams]]\nname = {}\nenergy = {}", quote(name), player.energy).unwrap();
+    }
+    out.push_str(&overrides);
+
+    Ok(out)
+}
@@ -1,48 +1,366 @@
 use dashmap::DashMap;
-use serde_json;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use uuid::Uuid;
-use ws;
 
 use crate::board::*;
+use crate::config::Config;
 use crate::data::{Position, TileType, Unit};
-use crate::data::{Request, Response};
+use crate::data::{CellChange, Envelope, ErrorCode, Reply, Request, Response, Viewport};
+use crate::game::{Match, Outcome, OPEN_WORLD};
+use crate::replay::ReplayEvent;
+use crate::snapshot::Snapshot;
 use crate::server;
 use crate::constants;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
-pub const PROTOCOL: &'static str = "game-of-strife";
+pub const PROTOCOL: &str = "game-of-strife";
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// How responses are put on the wire. MessagePack replies are binary frames
+// with structs encoded as arrays in field declaration order.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+// Subprotocols are `game-of-strife`, optionally followed by `.v<version>` and
+// then `.msgpack`. Without a version suffix, version 1 is assumed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Protocol {
+    pub version: u32,
+    pub encoding: Encoding,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self {
+            version: 1,
+            encoding: Encoding::Json,
+        }
+    }
+}
+
+impl Protocol {
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut parts = name.split('.');
+        if parts.next()? != PROTOCOL {
+            return None;
+        }
+
+        let mut protocol = Protocol::default();
+        for part in parts {
+            if part == "msgpack" {
+                protocol.encoding = Encoding::MessagePack;
+            } else {
+                protocol.version = part.strip_prefix('v')?.parse().ok()?;
+            }
+        }
+        Some(protocol)
+    }
+
+    pub fn is_supported(self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version)
+    }
+}
 
 // todo how to not use raw pointers
 pub struct Server {
     pub running: Arc<AtomicBool>,
-    pub board: Arc<RwLock<Board>>,
     pub clients: DashMap<Uuid, ClientHandler>,
+    pub matches: DashMap<u64, Arc<Match>>,
+    pub config: Config,
+
+    // Players waiting for a match, in the order they queued.
+    lobby: Mutex<Vec<(Uuid, String)>>,
+    next_match_id: AtomicU64,
 }
 
 impl Server {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(config: Config) -> Self {
+        let server = Self {
             running: Arc::new(AtomicBool::new(true)),
-            board: Arc::new(Board::new().into()),
             clients: DashMap::new(),
+            matches: DashMap::new(),
+            lobby: Mutex::new(Vec::new()),
+            next_match_id: AtomicU64::new(OPEN_WORLD + 1),
+            config,
+        };
+
+        if server.config.lobby.open_world {
+            let board = server.open_world_board();
+            let game = Match::new(OPEN_WORLD, board, Vec::new(), 0, &server.config);
+            server.matches.insert(OPEN_WORLD, Arc::new(game));
         }
+        server
+    }
+
+    fn open_world_board(&self) -> Board {
+        let config = &self.config;
+        let game = Arc::new(config.game.clone());
+        let new_board = || match config.server.seed {
+            Some(seed) => Board::with_seed(game.clone(), seed),
+            None => Board::new(game.clone()),
+        };
+
+        let restored = Snapshot::load_latest(&config.server.snapshot_dir).and_then(|snapshot| {
+            snapshot
+                .map(|snapshot| snapshot.restore(game.clone()))
+                .transpose()
+        });
+        match restored {
+            Ok(Some(mut board)) => {
+                // Nobody is connected yet; everyone gets the usual grace period
+                // to come back.
+                let gen = board.generation();
+                board
+                    .players_mut()
+                    .for_each(|player| player.disconnected_at = Some(gen));
+                info!(
+                    "Restored board (seed {}) at generation {} from snapshot.",
+                    board.seed(),
+                    board.generation()
+                );
+                board
+            }
+            Ok(None) => {
+                let board = new_board();
+                info!("Created board with seed {}.", board.seed());
+                board
+            }
+            Err(err) => {
+                warn!("Failed to restore snapshot, starting over: {}", err);
+                new_board()
+            }
+        }
+    }
+
+    // Starts ticking every match created so far.
+    pub fn start(self: &Arc<Self>) {
+        for game in self.matches.iter() {
+            game.value().start(Arc::clone(self));
+        }
+    }
+
+    pub fn get_match(&self, id: u64) -> Option<Arc<Match>> {
+        self.matches.get(&id).map(|game| Arc::clone(game.value()))
     }
 
     pub fn broadcast(&self, data: &Response) {
         self.clients.iter().for_each(|e| e.value().send(data));
     }
 
+    // Sends to everyone playing or watching a match.
+    pub fn broadcast_to(&self, game: u64, data: &Response) {
+        self.clients
+            .iter()
+            .filter(|e| e.value().watching() == game)
+            .for_each(|e| e.value().send(data));
+    }
+
+    // Pushes each subscribed client the part of a generation's changes that
+    // falls inside its viewport.
+    pub fn send_deltas(&self, game: u64, gen: usize, changes: &[CellChange]) {
+        self.clients.iter().filter(|e| e.value().watching() == game).for_each(|e| {
+            let client = e.value();
+            if let Some(viewport) = client.viewport() {
+                client.send(&Response::DELTA {
+                    gen,
+                    changes: viewport.filter(changes),
+                });
+            }
+        });
+    }
+
+    // Tells everyone playing a match how much energy they have left.
+    pub fn send_energy(&self, game: u64, energy: &HashMap<Uuid, u32>) {
+        self.clients.iter().filter(|e| e.value().watching() == game).for_each(|e| {
+            let client = e.value();
+            if let Some(&erg) = energy.get(&client.id) {
+                client.send(&Response::ENERGY_UPDATE { erg });
+            }
+        });
+    }
+
+    pub fn find_session(&self, token: Uuid) -> Option<Arc<Match>> {
+        self.matches
+            .iter()
+            .find(|game| {
+                game.value()
+                    .board
+                    .read()
+                    .is_ok_and(|board| board.find_player_by_token(token).is_some())
+            })
+            .map(|game| Arc::clone(game.value()))
+    }
+
+    pub fn queue(self: &Arc<Self>, id: Uuid, name: String) {
+        if let Ok(mut lobby) = self.lobby.lock() {
+            match lobby.iter_mut().find(|(queued, _)| *queued == id) {
+                Some(entry) => entry.1 = name,
+                None => lobby.push((id, name)),
+            }
+        }
+        self.fill_matches();
+        self.update_lobby();
+    }
+
+    pub fn leave_queue(&self, id: Uuid) -> bool {
+        let left = self.lobby.lock().is_ok_and(|mut lobby| {
+            let len = lobby.len();
+            lobby.retain(|&(queued, _)| queued != id);
+            lobby.len() != len
+        });
+        if left {
+            self.update_lobby();
+        }
+        left
+    }
+
+    fn update_lobby(&self) {
+        if let Ok(lobby) = self.lobby.lock() {
+            let update = Response::QUEUED {
+                players: lobby.len(),
+                needed: self.config.lobby.players_per_match,
+            };
+            for (id, _) in lobby.iter() {
+                if let Some(client) = self.clients.get(id) {
+                    client.value().send(&update);
+                }
+            }
+        }
+    }
+
+    // Starts matches for as long as enough players are waiting and there is
+    // room for another match.
+    fn fill_matches(self: &Arc<Self>) {
+        let settings = &self.config.lobby;
+        let size = settings.players_per_match.max(2);
+        loop {
+            let running = self.matches.iter().filter(|game| !game.value().is_open()).count();
+            if running >= settings.max_matches {
+                return;
+            }
+
+            // Bots fill whatever seats nobody is waiting for, if allowed to.
+            let players = match self.lobby.lock() {
+                Ok(mut lobby) if lobby.len() >= size => lobby.drain(..size).collect::<Vec<_>>(),
+                Ok(mut lobby) if self.config.bots.fill_matches && !lobby.is_empty() => lobby.drain(..).collect(),
+                _ => return,
+            };
+            let bots = size - players.len();
+            self.start_match(players, bots);
+        }
+    }
+
+    fn start_match(self: &Arc<Self>, players: Vec<(Uuid, String)>, bots: usize) {
+        let id = self.next_match_id.fetch_add(1, Ordering::SeqCst);
+
+        let mut config = self.config.game.clone();
+        config.x_size = self.config.lobby.board_side(players.len() + bots);
+        config.y_size = config.x_size;
+        let config = Arc::new(config);
+        let board = match self.config.server.seed {
+            Some(seed) => Board::with_seed(config, seed.wrapping_add(id)),
+            None => Board::new(config),
+        };
+
+        let game = Arc::new(Match::new(id, board, players.clone(), bots, &self.config));
+        if let Ok(mut board) = game.board.write() {
+            info!(
+                "Starting match {} ({}x{}, seed {}) with {} players and {} bots.",
+                id,
+                board.config().x_size,
+                board.config().y_size,
+                board.seed(),
+                players.len(),
+                bots
+            );
+
+            for (player, name) in players {
+                let client = match self.clients.get(&player) {
+                    Some(client) => client.value().clone(),
+                    None => continue,
+                };
+
+                if game.spawn(&mut board, player, name) {
+                    client.join(Some(Arc::clone(&game)));
+                    client.send(&Response::MATCH_START { match_id: id });
+                    client.identify(None, &board);
+                    client.send_phase(&game);
+                } else {
+                    client.error(
+                        None,
+                        ErrorCode::NO_ROOM,
+                        "Could not find a free spot on the board.".to_owned(),
+                    );
+                }
+            }
+            game.spawn_bots(&mut board);
+
+            self.broadcast_to(id, &Response::PALETTE {
+                teams: board.palette(),
+            });
+        }
+
+        self.matches.insert(id, Arc::clone(&game));
+        game.start(Arc::clone(self));
+    }
+
+    pub fn end_match(self: &Arc<Self>, id: u64, outcome: Outcome) {
+        info!(
+            "Match {} is over ({:?}), winner: {:?}.",
+            id, outcome.condition, outcome.winner
+        );
+        for standing in &outcome.standings {
+            info!(
+                "  {} {} cells={} alive={} peak={} kills={} spent={} earned={} survived={}",
+                standing.id,
+                standing.name.as_deref().unwrap_or("-"),
+                standing.cells,
+                standing.alive,
+                standing.peak_cells,
+                standing.kills,
+                standing.energy_spent,
+                standing.energy_earned,
+                standing.generations_survived
+            );
+        }
+
+        let over = Response::GAME_OVER {
+            match_id: id,
+            condition: outcome.condition,
+            winner: outcome.winner,
+            standings: outcome.standings,
+        };
+        self.clients
+            .iter()
+            .filter(|e| e.value().game().is_some_and(|game| game.id == id))
+            .for_each(|e| {
+                let client = e.value();
+                client.send(&over);
+                client.join(None);
+            });
+
+        self.matches.remove(&id);
+        self.fill_matches();
+    }
+
     pub fn new_client(arcself: Arc<Self>, out: ws::Sender) -> ClientHandler {
         let mut client = ClientHandler {
             id: Uuid::new_v4(),
             name: None,
             server: arcself.clone(),
             out: Arc::new(out),
+            protocol: Protocol::default(),
+            game: Arc::new(Mutex::new(None)),
+            viewport: Arc::new(Mutex::new(None)),
         };
 
         debug!("Creating a new client (id: {}).", client.id);
@@ -67,132 +385,101 @@ pub struct ClientHandler {
     pub server: Arc<Server>,
 
     pub out: Arc<ws::Sender>,
+    pub protocol: Protocol,
+
+    // Shared between the clones of a handler, so game threads see which match
+    // the connection is in and what it subscribed to.
+    pub game: Arc<Mutex<Option<Arc<Match>>>>,
+    pub viewport: Arc<Mutex<Option<Viewport>>>,
 }
 
 impl ws::Handler for ClientHandler {
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         // let server = unsafe { &mut *self.server };
-        match msg {
-            ws::Message::Text(buf) => match serde_json::from_str::<Request>(&buf) {
-                Ok(Request::NEW_PLAYER {
-                    username
-                }) => {
-                    self.name = Some(username.clone());
-                    self.server.board.write().map(|mut board| {
-                        board.get_player_mut(self.id).map(|player| { player.name = Some(username); });
-                    });
-                    Ok(())
-                }
-                Ok(Request::REQUEST_FRAME {
-                    x_origin,
-                    y_origin,
-                    x_size,
-                    y_size,
-                }) => {
-                    self.server
-                        .board
-                        .read()
-                        .map(|board| board.get_window(x_origin, y_origin, x_size, y_size))
-                        .map(|window| {
-                            self.send(&Response::FRAME {
-                                x_size: window[0].len(),
-                                y_size: window.len(),
-                                window,
-                            });
-                        });
-                    Ok(())
-                }
-                Ok(Request::PUT {
-                    position,
-                    tile
-                }) => {
-                    self.server.board.write().map(|mut board| {
-                        if board.get(position).is_empty() {
-                            if let Some(player) = board.get_player_mut(self.id) {
-                                if tile.get_cost() < player.energy {
-                                    player.energy -= tile.get_cost();
-                                    self.send(&Response::ENERGY_UPDATE {
-                                        erg: player.energy
-                                    });
-                                    board.set(position, Unit::new_unit(self.id, position, tile));
-                                } else {
-                                    self.send(&Response::NOTICE {
-                                        string: format!("Insufficient energy (cost is {}).", tile.get_cost())
-                                    });
-                                }
-                            }
-                        }
-                    });
-                    Ok(())
-                }
-                Ok(Request::EXIT_GAME) => {
-                    self.disconnect();
-                    Ok(())
-                }
-                _ => Err(ws::Error::new(ws::ErrorKind::Protocol, "Unrecognized data")),
-                Err(_) => Err(ws::Error::new(
-                    ws::ErrorKind::Protocol,
-                    "Unparsable data sent",
-                )),
-            },
-            ws::Message::Binary(_) => Err(ws::Error::new(
-                ws::ErrorKind::Protocol,
-                "Binary not accepted",
-            )),
+
+        // Either encoding is accepted, whatever was negotiated for replies.
+        let envelope = match msg {
+            ws::Message::Text(buf) => {
+                serde_json::from_str::<Envelope>(&buf).map_err(|err| err.to_string())
+            }
+            ws::Message::Binary(buf) => {
+                rmp_serde::from_slice::<Envelope>(&buf).map_err(|err| err.to_string())
+            }
+        };
+
+        match envelope {
+            Ok(Envelope {
+                request_id,
+                request,
+            }) => self.handle(request_id, request),
+            Err(err) => self.error(None, ErrorCode::BAD_REQUEST, err),
         }
+        Ok(())
     }
 
     fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
-        let id = self.id;
-        info!("Identified client with {}", id);
-
-        self.server.board.write().map(|mut board| {
-            if let Some(spawn_pos) = board.find_random_safe_position(5) {
-                board.add_player(PlayerInformation {
-                    id,
-                    name: None,
-                    energy: constants::INIT_ERG,
-                });
-
-                // care package
-                let queen = Unit::new_queen(id, spawn_pos);
-                board.set(spawn_pos, queen);
-
-                let feeder_pos = Position { x: spawn_pos.x, y: spawn_pos.y + 1 };
-                board.set(feeder_pos, queen.spawn_unit(feeder_pos, TileType::FEEDER));
-
-                // board.set(Position { x: spawn_pos.x - 2, y: spawn_pos.y - 2 }, queen.spawn_unit(TileType::SPAWNER));
-
-                if let Ok(data) = serde_json::to_string(&Response::IDENTIFY {
-                    id: id,
-                    origin: spawn_pos,
-                    x_size: constants::X_SIZE,
-                    y_size: constants::Y_SIZE,
-                }) {
-                    self.out.send(data).map_err(|_| warn!("Failed to send output data"));
-                } else {
-                    warn!("Failed to parse output data")
-                }
-            } else {
-                self.disconnect()
-            }
-        });
+        info!("Identified client with {}", self.id);
 
+        if self.protocol.is_supported() {
+            self.send(&Response::HELLO {
+                version: self.protocol.version,
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            });
+        } else {
+            self.error(
+                None,
+                ErrorCode::UNSUPPORTED_VERSION,
+                format!(
+                    "Protocol version {} is not supported (server speaks {} to {}).",
+                    self.protocol.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            );
+            self.out.close(ws::CloseCode::Protocol)?;
+        }
         Ok(())
     }
 
     fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
         let mut response = ws::Response::from_request(req)?;
-        response.set_protocol(PROTOCOL);
+
+        // Honour the client's order of preference. A client that only offers
+        // versions we do not speak still gets one of its own protocols back,
+        // so it can read the error sent once the connection opens.
+        let offered = req
+            .protocols()?
+            .into_iter()
+            .filter_map(|name| Protocol::parse(name).map(|protocol| (name, protocol)))
+            .collect::<Vec<_>>();
+        let accepted = offered
+            .iter()
+            .find(|(_, protocol)| protocol.is_supported())
+            .or_else(|| offered.first());
+
+        match accepted {
+            Some(&(name, protocol)) => {
+                response.set_protocol(name);
+                self.protocol = protocol;
+            }
+            None => response.set_protocol(PROTOCOL),
+        }
+        self.server.clients.insert(self.id, self.clone());
+
         Ok(response)
     }
 
     fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
         self.server.remove_client(self.id, false);
+        self.server.leave_queue(self.id);
 
-        self.server.board.write().map(|mut board| {
-            board.remove_player(self.id);
-        });
+        // The colony keeps going for a while so the player can resume it; the
+        // game thread removes it once the grace period runs out.
+        if let Some(Ok(mut board)) = self.game().as_ref().map(|game| game.board.write()) {
+            let gen = board.generation();
+            if let Some(player) = board.get_player_mut(self.id) {
+                player.disconnected_at = Some(gen);
+            }
+        }
 
         match code {
             ws::CloseCode::Normal => info!("Client (id: {}) has closed the connection.", self.id),
@@ -206,11 +493,311 @@ impl ws::Handler for ClientHandler {
 }
 
 impl ClientHandler {
+    fn handle(&mut self, request_id: Option<u64>, request: Request) {
+        match request {
+            Request::NEW_PLAYER { username } => {
+                self.name = Some(username.clone());
+                if let Some(game) = self.game() {
+                    if let Ok(mut board) = game.board.write() {
+                        if let Some(player) = board.get_player_mut(self.id) {
+                            player.name = Some(username);
+                            self.server.broadcast_to(game.id, &Response::PALETTE {
+                                teams: board.palette(),
+                            });
+                            return;
+                        }
+                    }
+                }
+
+                let game = match self.server.get_match(OPEN_WORLD) {
+                    Some(game) => game,
+                    None => {
+                        self.error(
+                            request_id,
+                            ErrorCode::BAD_REQUEST,
+                            "There is no open world on this server, queue for a match instead."
+                                .to_owned(),
+                        );
+                        return;
+                    }
+                };
+                if let Ok(mut board) = game.board.write() {
+                    if game.spawn(&mut board, self.id, username) {
+                        self.join(Some(Arc::clone(&game)));
+                        self.server.broadcast_to(game.id, &Response::PALETTE {
+                            teams: board.palette(),
+                        });
+                        self.identify(request_id, &board);
+                        self.send_phase(&game);
+                    } else {
+                        self.error(
+                            request_id,
+                            ErrorCode::NO_ROOM,
+                            "Could not find a free spot on the board.".to_owned(),
+                        );
+                        self.disconnect()
+                    }
+                };
+            }
+            Request::QUEUE { username } => {
+                if self.playing() {
+                    self.error(
+                        request_id,
+                        ErrorCode::BAD_REQUEST,
+                        "Already in a game.".to_owned(),
+                    );
+                    return;
+                }
+                self.name = Some(username.clone());
+                self.server.queue(self.id, username);
+            }
+            Request::LEAVE_QUEUE => {
+                self.server.leave_queue(self.id);
+            }
+            Request::RESUME { token } => {
+                let game = self.server.find_session(token);
+                if let Some(Ok(mut board)) = game.as_ref().map(|game| game.board.write()) {
+                    let resumable = board
+                        .find_player_by_token(token)
+                        .and_then(|id| board.get_player_mut(id))
+                        .filter(|player| player.disconnected_at.is_some());
+
+                    if let Some(player) = resumable {
+                        player.disconnected_at = None;
+                        self.name = player.name.clone();
+
+                        let id = player.id;
+                        info!("Client (id: {}) resumed the session of {}.", self.id, id);
+                        self.server.leave_queue(self.id);
+                        self.server.clients.remove(&self.id);
+                        self.id = id;
+                        self.server.clients.insert(id, self.clone());
+                        self.join(game.clone());
+
+                        self.send(&Response::PALETTE {
+                            teams: board.palette(),
+                        });
+                        self.identify(request_id, &board);
+                        if let Some(game) = &game {
+                            self.send_phase(game);
+                        }
+                        return;
+                    }
+                }
+                self.error(
+                    request_id,
+                    ErrorCode::INVALID_SESSION,
+                    "Session is unknown, expired or still active.".to_owned(),
+                );
+            }
+            Request::REQUEST_FRAME {
+                x_origin,
+                y_origin,
+                x_size,
+                y_size,
+            } => self.send_frame(request_id, x_origin, y_origin, x_size, y_size),
+            Request::SUBSCRIBE {
+                x_origin,
+                y_origin,
+                x_size,
+                y_size,
+            } => {
+                if let Ok(mut viewport) = self.viewport.lock() {
+                    *viewport = Some(Viewport {
+                        x_origin,
+                        y_origin,
+                        x_size,
+                        y_size,
+                    });
+                }
+
+                // Changes made before this frame was read may show up again
+                // in the next delta, which is harmless as deltas are
+                // idempotent.
+                self.send_frame(request_id, x_origin, y_origin, x_size, y_size);
+            }
+            Request::PUT { position, tile } => {
+                let game = match self.game() {
+                    Some(game) => game,
+                    None => {
+                        self.error(request_id, ErrorCode::NOT_IN_GAME, "You are not in a game.".to_owned());
+                        return;
+                    }
+                };
+                if let Ok(mut board) = game.board.write() {
+                    match game.put(&mut board, self.id, position, tile) {
+                        Ok(erg) => self.reply(request_id, &Response::ENERGY_UPDATE { erg }),
+                        Err(err) => self.error(request_id, err.code(), err.to_string()),
+                    }
+                };
+            }
+            Request::SET_RALLY { area, position } => {
+                let game = match self.game() {
+                    Some(game) => game,
+                    None => {
+                        self.error(request_id, ErrorCode::NOT_IN_GAME, "You are not in a game.".to_owned());
+                        return;
+                    }
+                };
+                if let Ok(mut board) = game.board.write() {
+                    match game.set_rally(&mut board, self.id, area, position) {
+                        Ok(units) => self.reply(request_id, &Response::ORDERED { units }),
+                        Err(err) => self.error(request_id, err.code(), err.to_string()),
+                    }
+                };
+            }
+            Request::SET_STANCE { area, stance } => {
+                let game = match self.game() {
+                    Some(game) => game,
+                    None => {
+                        self.error(request_id, ErrorCode::NOT_IN_GAME, "You are not in a game.".to_owned());
+                        return;
+                    }
+                };
+                if let Ok(mut board) = game.board.write() {
+                    match game.set_stance(&mut board, self.id, area, stance) {
+                        Ok(units) => self.reply(request_id, &Response::ORDERED { units }),
+                        Err(err) => self.error(request_id, err.code(), err.to_string()),
+                    }
+                };
+            }
+            Request::EXIT_GAME => {
+                if let Some(game) = self.game() {
+                    if let Ok(mut board) = game.board.write() {
+                        if board.get_player(self.id).is_some() {
+                            board.remove_player(self.id);
+                            game.record(board.generation(), ReplayEvent::LEAVE { id: self.id });
+                        }
+                    }
+                }
+                self.server.leave_queue(self.id);
+                self.disconnect();
+            }
+        }
+    }
+
+    fn send_frame(
+        &self,
+        request_id: Option<u64>,
+        x_origin: usize,
+        y_origin: usize,
+        x_size: usize,
+        y_size: usize,
+    ) {
+        let game = match self.server.get_match(self.watching()) {
+            Some(game) => game,
+            None => {
+                self.error(request_id, ErrorCode::NOT_IN_GAME, "Nothing to watch.".to_owned());
+                return;
+            }
+        };
+        if let Ok(window) = game
+            .board
+            .read()
+            .map(|board| board.get_window(x_origin, y_origin, x_size, y_size))
+        {
+            self.reply(
+                request_id,
+                &Response::FRAME {
+                    x_size: window.first().map_or(0, Vec::len),
+                    y_size: window.len(),
+                    window,
+                },
+            );
+        };
+    }
+
+    pub fn viewport(&self) -> Option<Viewport> {
+        self.viewport.lock().ok().and_then(|viewport| *viewport)
+    }
+
+    pub fn game(&self) -> Option<Arc<Match>> {
+        self.game.lock().ok().and_then(|game| game.clone())
+    }
+
+    // The match this connection gets updates from. Anyone not in a match
+    // spectates the open world.
+    pub fn watching(&self) -> u64 {
+        self.game().map_or(OPEN_WORLD, |game| game.id)
+    }
+
+    // Whether this connection has a colony on its match's board.
+    fn playing(&self) -> bool {
+        self.game().is_some_and(|game| {
+            game.board
+                .read()
+                .is_ok_and(|board| board.get_player(self.id).is_some())
+        })
+    }
+
+    // Moves the connection to another match. Its viewport is dropped, as it
+    // was for a different board; clients subscribe again after MATCH_START.
+    pub fn join(&self, game: Option<Arc<Match>>) {
+        if let Ok(mut current) = self.game.lock() {
+            *current = game;
+        }
+        if let Ok(mut viewport) = self.viewport.lock() {
+            *viewport = None;
+        }
+    }
+
+    pub fn identify(&self, request_id: Option<u64>, board: &Board) {
+        if let Some(player) = board.get_player(self.id) {
+            self.reply(
+                request_id,
+                &Response::IDENTIFY {
+                    id: self.id,
+                    team: board.team_index(self.id),
+                    token: player.token,
+                    origin: board.queen_position(self.id).unwrap_or_default(),
+                    x_size: board.config().x_size,
+                    y_size: board.config().y_size,
+                },
+            );
+        }
+    }
+
+    pub fn send_phase(&self, game: &Match) {
+        if let Some(phase) = game.phase() {
+            self.send(&phase);
+        }
+    }
+
     pub fn send(&self, data: &Response) {
+        self.send_encoded(data);
+    }
+
+    // Replies that echo a request id are always maps, also in MessagePack.
+    pub fn reply(&self, request_id: Option<u64>, data: &Response) {
+        match request_id {
+            Some(request_id) => self.send_encoded(&Reply {
+                request_id,
+                response: data,
+            }),
+            None => self.send_encoded(data),
+        }
+    }
+
+    pub fn error(&self, request_id: Option<u64>, code: ErrorCode, message: String) {
+        debug!("Client (id: {}) error {:?}: {}", self.id, code, message);
+        self.send(&Response::ERROR {
+            code,
+            message,
+            request_id,
+        });
+    }
+
+    fn send_encoded<T: serde::Serialize>(&self, data: &T) {
         debug!("Sending message...");
-        self.out
-            .send(serde_json::to_string(data).expect("Can not serialize"))
-            .expect("Error while sending");
+        let msg = match self.protocol.encoding {
+            Encoding::Json => {
+                ws::Message::Text(serde_json::to_string(data).expect("Can not serialize"))
+            }
+            Encoding::MessagePack => {
+                ws::Message::Binary(rmp_serde::to_vec(data).expect("Can not serialize"))
+            }
+        };
+        self.out.send(msg).expect("Error while sending");
     }
 
     pub fn disconnect(&self) {
@@ -0,0 +1,109 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::board::{Board, PlayerInformation, PlayerStats};
+use crate::config::GameConfig;
+use crate::data::{Position, Unit};
+
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+const LATEST: &str = "latest.json";
+
+// Everything needed to bring a board back exactly as it was, including where
+// its RNG stream had got to.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub x_size: usize,
+    pub y_size: usize,
+    pub seed: u64,
+    pub rng_word_pos: u128,
+    pub generation: usize,
+    pub players: Vec<PlayerInformation>,
+    // Palette order, with the stats of every team that has played. Defaulted
+    // so older snapshots get as far as the version check.
+    #[serde(default)]
+    pub teams: Vec<(Uuid, PlayerStats)>,
+    pub units: Vec<(Position, Unit)>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Snapshot {
+    pub fn capture(board: &Board) -> Self {
+        let mut players = board.players().cloned().collect::<Vec<_>>();
+        players.sort_unstable_by_key(|player| player.id);
+
+        Self {
+            version: SNAPSHOT_VERSION,
+            x_size: board.config().x_size,
+            y_size: board.config().y_size,
+            seed: board.seed(),
+            rng_word_pos: board.rng_word_pos(),
+            generation: board.generation(),
+            players,
+            teams: board.teams().collect(),
+            units: board.units().collect(),
+        }
+    }
+
+    pub fn restore(self, config: Arc<GameConfig>) -> io::Result<Board> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported snapshot version {} (expected {})",
+                self.version, SNAPSHOT_VERSION
+            )));
+        }
+        if (self.x_size, self.y_size) != (config.x_size, config.y_size) {
+            return Err(invalid_data(format!(
+                "Snapshot board is {}x{}, expected {}x{}",
+                self.x_size, self.y_size, config.x_size, config.y_size
+            )));
+        }
+
+        let mut board = Board::with_seed(config, self.seed);
+        board.resume_at(self.generation, self.rng_word_pos);
+        let too_many = || invalid_data("Snapshot has more teams than there are team indices".to_owned());
+        board.restore_teams(self.teams).ok_or_else(too_many)?;
+        for player in self.players {
+            board.add_player(player).ok_or_else(too_many)?;
+        }
+        for (position, unit) in self.units {
+            if !board.in_bounds(position) {
+                return Err(invalid_data(format!("Unit out of bounds at {:?}", position)));
+            }
+            board.set(position, unit);
+        }
+        Ok(board)
+    }
+
+    // Written to a temporary file first and renamed over the previous
+    // snapshot, so a crash mid-write never leaves a torn file behind.
+    pub fn save(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let tmp = dir.join(format!("{}.tmp", LATEST));
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut out, self)?;
+        out.flush()?;
+        drop(out);
+
+        fs::rename(tmp, dir.join(LATEST))
+    }
+
+    pub fn load_latest(dir: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let path = dir.as_ref().join(LATEST);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let snapshot = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(Some(snapshot))
+    }
+}
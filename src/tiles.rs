@@ -0,0 +1,110 @@
+use rand::Rng;
+use serde::de::{Deserializer, Error, IntoDeserializer};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::data::TileType;
+
+const DEFAULT_TILES: &str = include_str!("../tiles.toml");
+
+// What a tile costs and how it behaves. See `tiles.toml` for what each field
+// does; anything left out means the tile does not do that.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TileDef {
+    pub cost: Option<u32>,
+    pub base_hp: u32,
+    pub armor_cap: u32,
+    pub feed_range: Option<u16>,
+    pub bolster_range: Option<u16>,
+    pub damage: u32,
+    pub aggro_radius: Option<u16>,
+    pub holds_position: bool,
+    pub spawn_range: u16,
+    pub income: u32,
+    #[serde(deserialize_with = "tile_keys")]
+    pub spawns: BTreeMap<TileType, u32>,
+}
+
+impl TileDef {
+    // Rolls the spawn table. Tiles without one never draw from the RNG.
+    pub fn pick_spawn<R: Rng>(&self, rng: &mut R) -> Option<TileType> {
+        let total = self.spawns.values().sum::<u32>();
+        if total == 0 {
+            return None;
+        }
+
+        let mut roll = rng.gen_range(0, total);
+        for (&tile, &weight) in &self.spawns {
+            if roll < weight {
+                return Some(tile);
+            }
+            roll -= weight;
+        }
+        unreachable!()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TileSet(#[serde(deserialize_with = "tile_keys")] BTreeMap<TileType, TileDef>);
+
+// TOML only has string keys, so tile names are parsed by hand.
+fn tile_keys<'de, D, V>(deserializer: D) -> Result<BTreeMap<TileType, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    BTreeMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| {
+            let tile = TileType::deserialize(key.as_str().into_deserializer())
+                .map_err(|err: serde::de::value::Error| D::Error::custom(err))?;
+            Ok((tile, value))
+        })
+        .collect()
+}
+
+impl Default for TileSet {
+    fn default() -> Self {
+        Self::parse(DEFAULT_TILES).expect("built-in tile definitions are valid")
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl TileSet {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let tiles: TileSet = toml::from_str(text).map_err(|err| invalid_data(err.to_string()))?;
+
+        if !tiles.0.contains_key(&TileType::QUEEN) {
+            return Err(invalid_data("QUEEN has no definition".to_owned()));
+        }
+        for (tile, def) in &tiles.0 {
+            if *tile == TileType::EMPTY || def.spawns.contains_key(&TileType::EMPTY) {
+                return Err(invalid_data("EMPTY is not a tile".to_owned()));
+            }
+            if let Some(spawn) = def.spawns.keys().find(|spawn| !tiles.0.contains_key(spawn)) {
+                return Err(invalid_data(format!("{:?} spawns undefined tile {:?}", tile, spawn)));
+            }
+        }
+        Ok(tiles)
+    }
+
+    pub fn get(&self, tile: TileType) -> Option<&TileDef> {
+        self.0.get(&tile)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (TileType, &TileDef)> {
+        self.0.iter().map(|(&tile, def)| (tile, def))
+    }
+}
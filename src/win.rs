@@ -0,0 +1,53 @@
+use uuid::Uuid;
+
+use crate::board::Board;
+
+// Ways a lobby match can end. A match lists any number of them and ends as
+// soon as the first one is met.
+#[allow(non_camel_case_types)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WinCondition {
+    // The last player with a queen wins.
+    LAST_QUEEN_STANDING,
+    // Whoever has the most cells once the board reaches a generation.
+    MOST_CELLS { generations: usize },
+    // The first player to hold a share of the board's cells.
+    TERRITORY { percent: f64 },
+}
+
+impl WinCondition {
+    // `Some` once the condition ends the match, holding the winner. A match
+    // can also end in a draw, with nobody or several players tied on top.
+    pub fn check(&self, board: &Board, players: &[Uuid]) -> Option<Option<Uuid>> {
+        let alive = || players.iter().copied().filter(|&id| board.queen_position(id).is_some());
+
+        match *self {
+            WinCondition::LAST_QUEEN_STANDING => {
+                let mut alive = alive();
+                match (alive.next(), alive.next()) {
+                    (winner, None) => Some(winner),
+                    _ => None,
+                }
+            }
+            WinCondition::MOST_CELLS { generations } => {
+                if board.generation() < generations {
+                    return None;
+                }
+
+                let best = alive().map(|id| board.team_size(id)).max();
+                let mut leaders = alive().filter(|&id| Some(board.team_size(id)) == best);
+                match (leaders.next(), leaders.next()) {
+                    (winner, None) => Some(winner),
+                    _ => Some(None),
+                }
+            }
+            WinCondition::TERRITORY { percent } => {
+                let cells = (board.config().x_size * board.config().y_size) as f64;
+                alive()
+                    .find(|&id| board.team_size(id) as f64 >= cells * percent / 100.0)
+                    .map(Some)
+            }
+        }
+    }
+}
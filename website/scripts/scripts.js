@@ -1,5 +1,6 @@
 var WS;
 var UID;
+var TEAM;
 var USERNAME;
 var USERNAME_REGEX = "(?![_0-9a-zA-Z]+).";
 var IP = "celltomata.tech/ws/"; //"127.0.0.1:2794";
@@ -102,6 +103,7 @@ function launchGame() {
         switch (payload.type) {
             case "IDENTIFY":
                 UID = payload.id;
+                TEAM = payload.team;
                 //resizeGrid();
                 origin = payload.origin;
                 resizeGrid();
@@ -111,9 +113,9 @@ function launchGame() {
             case "FRAME":
                 fillCells(payload);
                 break;
-            case "GENERATION_PING":
+            case "DELTA":
                 if (shifting) return;
-                refreshGrid();
+                applyDelta(payload);
                 break;
             case "LEADERBOARD_UPDATE":
                 refreshLeaderboard(payload);
@@ -126,6 +128,28 @@ function launchGame() {
                 document.getElementById("notice").style.display = "initial";
                 document.getElementById("notice").innerHTML = payload.string;
                 break;
+            case "ERROR":
+                document.getElementById("notice").style.display = "initial";
+                document.getElementById("notice").innerHTML = payload.message;
+                break;
+            case "QUEUED":
+                document.getElementById("notice").style.display = "initial";
+                document.getElementById("notice").innerHTML =
+                    "Waiting for players (" + payload.players + "/" + payload.needed + ")";
+                break;
+            case "PHASE_CHANGE":
+                document.getElementById("notice").style.display = "initial";
+                document.getElementById("notice").innerHTML = payload.phase == "BUILD"
+                    ? "Build phase, " + Math.ceil(payload.remaining_ms / 1000) + "s to place cells"
+                    : "Evolving for " + payload.remaining_generations + " generations";
+                break;
+            case "GAME_OVER":
+                var winner = payload.standings.find(standing => standing.id == payload.winner);
+                document.getElementById("notice").style.display = "initial";
+                document.getElementById("notice").innerHTML = winner
+                    ? "Game over, " + (winner.id == UID ? "you win!" : winner.name + " wins.")
+                    : "Game over, nobody survived.";
+                break;
         }
     };
 }
@@ -181,6 +205,39 @@ function fillCells(payload) {
     }
 }
 
+// Clears every vacated cell first, then draws the new contents, so the order
+// of changes within a delta does not matter.
+function applyDelta(payload) {
+    ctx.lineWidth = cellLineWidth;
+    ctx.strokeStyle = "black";
+    ctx.font = `${cellSize / 1.5}px Arial`;
+    ctx.textAlign = "center";
+    ctx.textBaseline = "middle";
+
+    payload.changes.forEach(change => {
+        if (change.kind == "REMOVE") clearCell(change.position);
+        if (change.kind == "MOVE") clearCell(change.from);
+    });
+    payload.changes.forEach(change => {
+        if (change.kind == "ADD") drawCell(change.unit, change.position);
+        if (change.kind == "MOVE") drawCell(change.unit, change.to);
+    });
+}
+
+function clearCell(pos) {
+    var pxX = (pos.x - origin.x) * cellDims.x;
+    var pxY = (pos.y - origin.y) * cellDims.y;
+    ctx.clearRect(pxX, pxY, cellDims.x, cellDims.y);
+    ctx.beginPath();
+    ctx.rect(pxX, pxY, cellDims.x, cellDims.y);
+    ctx.stroke();
+}
+
+function drawCell(cell, pos) {
+    clearCell(pos);
+    fillCell(cell, pos.x - origin.x, pos.y - origin.y);
+}
+
 function fillCell(cell, x, y) {
     if (cell.tile != "EMPTY") {
         pxX = x * cellDims.x;
@@ -189,7 +246,7 @@ function fillCell(cell, x, y) {
         ctx.rect(pxX, pxY, cellDims.x, cellDims.y);
         ctx.stroke();
 
-        ctx.fillStyle = cell.team == UID ? "green" : "red";
+        ctx.fillStyle = cell.team == TEAM ? "green" : "red";
         ctx.fill();
         ctx.fillStyle = "white";
         ctx.fillText(cell.tile[0], pxX + (cellSize / 2), pxY + (cellSize / 2));
@@ -245,7 +302,7 @@ function resizeGrid() {
 
 function requestCells() {
     WS.send(JSON.stringify({
-        type : "REQUEST_FRAME",
+        type : "SUBSCRIBE",
         x_origin : origin.x,
         y_origin : origin.y,
         x_size : cellCounts.x,